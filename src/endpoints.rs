@@ -0,0 +1,295 @@
+//! Pool of RPC endpoints that batch blocks are indexed from.
+
+use crate::{connect, shared::*, RECONNECT_MAX_DELAY, RECONNECT_MIN_DELAY};
+use std::sync::Mutex;
+use subxt::{backend::legacy::LegacyRpcMethods, OnlineClient};
+use tokio::{
+    sync::Notify,
+    time::{self, Duration, Instant},
+};
+use tracing::{debug, error, info};
+
+/// Number of consecutive failures after which an endpoint is reconnected
+const ENDPOINT_RECONNECT_FAILURES: u32 = 3;
+
+/// Load and health of an endpoint
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EndpointHealth {
+    /// Maximum number of batch blocks to index from the endpoint at the same time
+    pub limit: Option<u32>,
+    /// Number of batch blocks being indexed from the endpoint
+    pub in_flight: u32,
+    /// Number of consecutive failed requests
+    pub failures: u32,
+    /// The endpoint is not used again until this time after a failure
+    pub retry_at: Option<Instant>,
+    /// The endpoint is on a different chain and is never used
+    pub disabled: bool,
+}
+
+impl EndpointHealth {
+    /// Whether a batch block can be indexed from the endpoint now.
+    pub fn is_available(&self, now: Instant) -> bool {
+        !self.disabled
+            && self.retry_at.is_none_or(|retry_at| retry_at <= now)
+            && self.limit.is_none_or(|limit| self.in_flight < limit)
+    }
+
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+
+    /// Takes the endpoint out of use for a delay that doubles with each consecutive failure.
+    pub fn record_failure(&mut self, now: Instant) {
+        self.failures += 1;
+        let delay = RECONNECT_MIN_DELAY
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(RECONNECT_MAX_DELAY);
+        self.retry_at = Some(now + delay);
+    }
+}
+
+/// Order and delay of the attempts to reconnect the head to a node. Each failed attempt moves on to the next endpoint and doubles the delay.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    urls: Vec<String>,
+    url_index: usize,
+    delay: Duration,
+}
+
+impl ReconnectPolicy {
+    pub fn new(urls: Vec<String>, url_index: usize) -> Self {
+        ReconnectPolicy {
+            urls,
+            url_index,
+            delay: RECONNECT_MIN_DELAY,
+        }
+    }
+
+    /// Endpoint to try next
+    pub fn url(&self) -> &str {
+        &self.urls[self.url_index]
+    }
+
+    /// Delay before the next attempt
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    pub fn record_failure(&mut self) {
+        self.url_index = (self.url_index + 1) % self.urls.len();
+        self.delay = (self.delay * 2).min(RECONNECT_MAX_DELAY);
+    }
+
+    /// Starts from the shortest delay the next time the connection is lost. The endpoint is kept.
+    pub fn record_success(&mut self) {
+        self.delay = RECONNECT_MIN_DELAY;
+    }
+}
+
+/// Returns the index of the available endpoint with the fewest batch blocks being indexed, skipping the endpoints in `exclude`.
+pub fn select_endpoint<'a>(
+    endpoints: impl Iterator<Item = &'a EndpointHealth>,
+    exclude: &[usize],
+    now: Instant,
+) -> Option<usize> {
+    endpoints
+        .enumerate()
+        .filter(|(index, health)| !exclude.contains(index) && health.is_available(now))
+        .min_by_key(|(_, health)| health.in_flight)
+        .map(|(index, _)| index)
+}
+
+type Client<R> = (
+    OnlineClient<<R as RuntimeIndexer>::RuntimeConfig>,
+    LegacyRpcMethods<<R as RuntimeIndexer>::RuntimeConfig>,
+);
+
+struct Endpoint<R: RuntimeIndexer + ?Sized> {
+    url: String,
+    client: Option<Client<R>>,
+    health: EndpointHealth,
+}
+
+/// Endpoints that batch blocks are spread across
+pub struct EndpointPool<R: RuntimeIndexer + ?Sized> {
+    endpoints: Mutex<Vec<Endpoint<R>>>,
+    released: Notify,
+}
+
+impl<R: RuntimeIndexer + ?Sized> Default for EndpointPool<R> {
+    fn default() -> Self {
+        EndpointPool {
+            endpoints: Vec::new().into(),
+            released: Notify::new(),
+        }
+    }
+}
+
+impl<R: RuntimeIndexer> EndpointPool<R> {
+    /// Adds an endpoint. If it could not be connected to, it is connected to when it is next used.
+    pub fn add(&self, endpoint: EndpointConfig, client: Option<Client<R>>) {
+        let mut health = EndpointHealth {
+            limit: endpoint.limit,
+            ..Default::default()
+        };
+        if client.is_none() {
+            health.record_failure(Instant::now());
+        }
+        self.endpoints.lock().unwrap().push(Endpoint {
+            url: endpoint.url,
+            client,
+            health,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.endpoints.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits until an endpoint not in `exclude` is available and reserves it for indexing a batch block. Returns `None` if every endpoint is excluded or disabled.
+    pub async fn acquire(&self, exclude: &[usize]) -> Option<EndpointGuard<'_, R>> {
+        loop {
+            let selected =
+                {
+                    let mut endpoints = self.endpoints.lock().unwrap();
+                    if endpoints.iter().enumerate().all(|(index, endpoint)| {
+                        endpoint.health.disabled || exclude.contains(&index)
+                    }) {
+                        return None;
+                    }
+                    let now = Instant::now();
+                    match select_endpoint(
+                        endpoints.iter().map(|endpoint| &endpoint.health),
+                        exclude,
+                        now,
+                    ) {
+                        Some(index) => {
+                            let endpoint = &mut endpoints[index];
+                            endpoint.health.in_flight += 1;
+                            Ok((index, endpoint.url.clone(), endpoint.client.clone()))
+                        }
+                        None => {
+                            let retry_at = endpoints
+                                .iter()
+                                .enumerate()
+                                .filter(|(index, endpoint)| {
+                                    !endpoint.health.disabled && !exclude.contains(index)
+                                })
+                                .filter_map(|(_, endpoint)| endpoint.health.retry_at)
+                                .filter(|retry_at| *retry_at > now)
+                                .min();
+                            Err((retry_at, self.released.notified()))
+                        }
+                    }
+                };
+            let (index, url, client) = match selected {
+                Ok(selected) => selected,
+                // Wait for a block to finish or for a failed endpoint to be tried again.
+                Err((Some(retry_at), released)) => {
+                    tokio::select! {
+                        _ = released => {}
+                        _ = time::sleep_until(retry_at) => {}
+                    }
+                    continue;
+                }
+                Err((None, released)) => {
+                    released.await;
+                    continue;
+                }
+            };
+            let mut guard = EndpointGuard {
+                pool: self,
+                index,
+                client: None,
+            };
+            let client = match client {
+                Some(client) => client,
+                None => match connect::<R>(&url).await {
+                    Ok(client) => {
+                        info!("Connected to endpoint: {}", url);
+                        self.endpoints.lock().unwrap()[index].client = Some(client.clone());
+                        client
+                    }
+                    Err(IndexError::WrongGenesisHash(genesis_hash)) => {
+                        error!(
+                            "Endpoint {} has wrong genesis hash: 0x{}",
+                            url,
+                            hex::encode(genesis_hash)
+                        );
+                        self.endpoints.lock().unwrap()[index].health.disabled = true;
+                        continue;
+                    }
+                    Err(err) => {
+                        error!("Failed to connect to endpoint {}: {}", url, err);
+                        guard.record_failure();
+                        continue;
+                    }
+                },
+            };
+            guard.client = Some(client);
+            return Some(guard);
+        }
+    }
+}
+
+/// Reservation of an endpoint for indexing a batch block. Released when dropped.
+pub struct EndpointGuard<'a, R: RuntimeIndexer + ?Sized> {
+    pool: &'a EndpointPool<R>,
+    index: usize,
+    client: Option<Client<R>>,
+}
+
+impl<R: RuntimeIndexer + ?Sized> EndpointGuard<'_, R> {
+    /// Position of the endpoint in the pool
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn api(&self) -> &OnlineClient<R::RuntimeConfig> {
+        &self.client.as_ref().unwrap().0
+    }
+
+    pub fn rpc(&self) -> &LegacyRpcMethods<R::RuntimeConfig> {
+        &self.client.as_ref().unwrap().1
+    }
+
+    pub fn record_success(&self) {
+        let mut endpoints = self.pool.endpoints.lock().unwrap();
+        let endpoint = &mut endpoints[self.index];
+        if endpoint.health.failures >= ENDPOINT_RECONNECT_FAILURES {
+            info!("Endpoint {} recovered.", endpoint.url);
+        }
+        endpoint.health.record_success();
+    }
+
+    /// Takes the endpoint out of use for a while so other endpoints are used instead.
+    pub fn record_failure(&self) {
+        let mut endpoints = self.pool.endpoints.lock().unwrap();
+        let endpoint = &mut endpoints[self.index];
+        endpoint.health.record_failure(Instant::now());
+        debug!(
+            "Endpoint {} failed {} times in a row.",
+            endpoint.url, endpoint.health.failures
+        );
+        // Replace the connection in case it has been lost.
+        if endpoint.health.failures == ENDPOINT_RECONNECT_FAILURES {
+            error!("Endpoint {} is unhealthy, reconnecting.", endpoint.url);
+            endpoint.client = None;
+        }
+    }
+}
+
+impl<R: RuntimeIndexer + ?Sized> Drop for EndpointGuard<'_, R> {
+    fn drop(&mut self) {
+        self.pool.endpoints.lock().unwrap()[self.index]
+            .health
+            .in_flight -= 1;
+        self.pool.released.notify_one();
+    }
+}
@@ -0,0 +1,258 @@
+//! Consistency checking of index databases.
+
+use crate::{
+    endpoints::EndpointPool, merge::SpanRecord, prune::key_block_number, shared::*, storage::Tree,
+    substrate::Indexer,
+};
+use ahash::AHashMap;
+use num_format::{Locale, ToFormattedString};
+use serde::Serialize;
+use std::sync::Arc;
+use subxt::{backend::legacy::LegacyRpcMethods, OnlineClient};
+use tokio::sync::RwLock;
+use tracing::info;
+use zerocopy::FromBytes;
+
+/// Inconsistency found in a database
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum FsckIssue {
+    /// Span entry that cannot be decoded
+    InvalidSpan { key: String },
+    /// Span that starts after it ends
+    ReversedSpan { start: u32, end: u32 },
+    /// Spans that include the same blocks
+    OverlappingSpans { first: Span, second: Span },
+    /// Spans that should have been merged
+    AdjacentSpans { first: Span, second: Span },
+    /// Key that is too short to contain a block number
+    InvalidKey { tree: String, key: String },
+    /// Keys for blocks that are not in any span
+    #[serde(rename_all = "camelCase")]
+    KeysOutsideSpans {
+        tree: String,
+        count: u64,
+        first_block: u32,
+        last_block: u32,
+    },
+    /// Keys of a re-fetched block that are not in the database
+    #[serde(rename_all = "camelCase")]
+    MissingKeys { block_number: u32, count: u32 },
+}
+
+/// Result of checking a database
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FsckReport {
+    pub span_count: u64,
+    pub key_count: u64,
+    pub checked_blocks: Vec<u32>,
+    pub issues: Vec<FsckIssue>,
+}
+
+impl FsckReport {
+    /// Whether no issues were found.
+    pub fn is_consistent(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Decodes all the span entries, reporting any that are invalid.
+pub fn check_spans(
+    span_db: &Tree,
+    issues: &mut Vec<FsckIssue>,
+) -> Result<Vec<SpanRecord>, IndexError> {
+    let mut records = Vec::new();
+    for item in span_db.iter() {
+        let (key, value) = item?;
+        let (Ok(end), Some(value)) = (
+            <[u8; 4]>::try_from(key.as_slice()),
+            SpanDbValue::read_from(value.as_slice()),
+        ) else {
+            issues.push(FsckIssue::InvalidSpan {
+                key: hex::encode(key),
+            });
+            continue;
+        };
+        let span = Span {
+            start: value.start.into(),
+            end: u32::from_be_bytes(end),
+        };
+        if span.start > span.end {
+            issues.push(FsckIssue::ReversedSpan {
+                start: span.start,
+                end: span.end,
+            });
+            continue;
+        }
+        records.push(SpanRecord {
+            span,
+            version: value.version.into(),
+            index_variant: value.index_variant == 1,
+        });
+    }
+    records.sort_by_key(|record| record.span.start);
+    for pair in records.windows(2) {
+        let (first, second) = (pair[0].span.clone(), pair[1].span.clone());
+        if second.start <= first.end {
+            issues.push(FsckIssue::OverlappingSpans { first, second });
+        } else if second.start == first.end + 1 {
+            issues.push(FsckIssue::AdjacentSpans { first, second });
+        }
+    }
+    Ok(records)
+}
+
+/// Merges sorted spans into the blocks they cover.
+fn covered_blocks(records: &[SpanRecord]) -> Vec<Span> {
+    let mut covered: Vec<Span> = Vec::new();
+    for record in records {
+        match covered.last_mut() {
+            Some(last) if record.span.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(record.span.end);
+            }
+            _ => covered.push(record.span.clone()),
+        }
+    }
+    covered
+}
+
+/// Checks that every key in the tree is for a block in a span, returning the number of keys.
+fn check_tree(
+    name: &str,
+    tree: &Tree,
+    covered: &[Span],
+    issues: &mut Vec<FsckIssue>,
+) -> Result<u64, IndexError> {
+    let mut key_count = 0;
+    let mut outside: Option<(u64, u32, u32)> = None;
+    for item in tree.iter() {
+        let (key, _) = item?;
+        key_count += 1;
+        let Some(block_number) = key_block_number(&key) else {
+            issues.push(FsckIssue::InvalidKey {
+                tree: name.to_owned(),
+                key: hex::encode(key),
+            });
+            continue;
+        };
+        let i = covered.partition_point(|span| span.end < block_number);
+        if covered
+            .get(i)
+            .is_some_and(|span| span.start <= block_number)
+        {
+            continue;
+        }
+        outside = Some(match outside {
+            Some((count, first_block, last_block)) => (
+                count + 1,
+                first_block.min(block_number),
+                last_block.max(block_number),
+            ),
+            None => (1, block_number, block_number),
+        });
+    }
+    if let Some((count, first_block, last_block)) = outside {
+        issues.push(FsckIssue::KeysOutsideSpans {
+            tree: name.to_owned(),
+            count,
+            first_block,
+            last_block,
+        });
+    }
+    Ok(key_count)
+}
+
+/// Checks the spans and keys of the database without connecting to a node.
+pub fn check_database<CT>(trees: &Trees<CT>) -> Result<FsckReport, IndexError> {
+    let mut report = FsckReport::default();
+    let records = check_spans(&trees.span, &mut report.issues)?;
+    report.span_count = records.len().try_into().unwrap();
+    let covered = covered_blocks(&records);
+    let mut names = trees.root.tree_names()?;
+    names.sort();
+    for name in names {
+        if name == b"span" || name == b"failed" {
+            continue;
+        }
+        let tree = trees.root.open_tree(&name)?;
+        report.key_count += check_tree(
+            &String::from_utf8_lossy(&name),
+            &tree,
+            &covered,
+            &mut report.issues,
+        )?;
+    }
+    Ok(report)
+}
+
+/// Selects up to `count` blocks spread evenly across the spans.
+pub fn sample_blocks(records: &[SpanRecord], count: u32) -> Vec<u32> {
+    let total: u64 = records
+        .iter()
+        .map(|record| u64::from(record.span.end - record.span.start) + 1)
+        .sum();
+    let count = u64::from(count).min(total);
+    let mut blocks = Vec::new();
+    for i in 0..count {
+        let mut offset = i * total / count;
+        for record in records {
+            let len = u64::from(record.span.end - record.span.start) + 1;
+            if offset < len {
+                blocks.push(record.span.start + u32::try_from(offset).unwrap());
+                break;
+            }
+            offset -= len;
+        }
+    }
+    blocks
+}
+
+/// Checks the database, then re-fetches `sample` blocks from the node to confirm that their keys are in the database.
+pub async fn fsck<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    api: OnlineClient<R::RuntimeConfig>,
+    rpc: LegacyRpcMethods<R::RuntimeConfig>,
+    sample: u32,
+) -> Result<FsckReport, IndexError> {
+    let mut report = check_database(trees)?;
+    let mut issues = Vec::new();
+    let records = check_spans(&trees.span, &mut issues)?;
+    let metadata_map_lock = Arc::new(RwLock::new(AHashMap::new()));
+    let indexers = [false, true].map(|index_variant| {
+        Indexer::<R>::new(
+            trees.clone(),
+            api.clone(),
+            rpc.clone(),
+            EndpointPool::default(),
+            index_variant,
+            metadata_map_lock.clone(),
+        )
+    });
+    for block_number in sample_blocks(&records, sample) {
+        // Only check event variants if they were indexed in the span.
+        let index_variant = records.iter().any(|record| {
+            record.span.start <= block_number
+                && block_number <= record.span.end
+                && record.index_variant
+        });
+        let count = indexers[usize::from(index_variant)]
+            .check_block(block_number)
+            .await?;
+        if count != 0 {
+            report.issues.push(FsckIssue::MissingKeys {
+                block_number,
+                count,
+            });
+        }
+        report.checked_blocks.push(block_number);
+    }
+    info!(
+        "Checked {} spans, {} keys and {} blocks: {} issues found.",
+        report.span_count.to_formatted_string(&Locale::en),
+        report.key_count.to_formatted_string(&Locale::en),
+        report.checked_blocks.len().to_formatted_string(&Locale::en),
+        report.issues.len().to_formatted_string(&Locale::en)
+    );
+    Ok(report)
+}
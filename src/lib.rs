@@ -3,6 +3,7 @@
 //! A library for indexing events from Substrate blockchains.
 
 #![feature(let_chains)]
+use ahash::AHashMap;
 use byte_unit::Byte;
 use futures::StreamExt;
 use signal_hook::{consts::TERM_SIGNALS, flag};
@@ -18,17 +19,27 @@ use subxt::{
 };
 use tokio::{
     join, spawn,
-    sync::{mpsc, watch},
+    sync::{mpsc, watch, RwLock},
+    time::{sleep, Duration},
 };
 use tracing::{error, info};
-use tracing_subscriber::filter::LevelFilter;
 
+pub mod endpoints;
+pub mod fsck;
+pub mod merge;
+pub mod prune;
+pub mod queue;
+pub mod schema;
 pub mod shared;
+pub mod snapshot;
+pub mod storage;
 pub mod substrate;
 pub mod substrate_pallets;
 pub mod websockets;
 
 use crate::shared::*;
+use endpoints::{EndpointPool, ReconnectPolicy};
+use storage::*;
 use substrate::*;
 use websockets::websockets_listen;
 
@@ -36,13 +47,13 @@ use websockets::websockets_listen;
 mod tests;
 
 pub fn open_trees<R: RuntimeIndexer>(
-    db_config: sled::Config,
-) -> Result<Trees<<R::ChainKey as IndexKey>::ChainTrees>, sled::Error> {
-    let db = db_config.open()?;
+    db: Db,
+) -> Result<Trees<<R::ChainKey as IndexKey>::ChainTrees>, StorageError> {
     let trees = Trees {
         root: db.clone(),
         span: db.open_tree(b"span")?,
         variant: db.open_tree(b"variant")?,
+        failed: db.open_tree(b"failed")?,
         // Each event parameter to be indexed has its own tree.
         substrate: SubstrateTrees::open(&db)?,
         chain: <R::ChainKey as IndexKey>::ChainTrees::open(&db)?,
@@ -50,41 +61,154 @@ pub fn open_trees<R: RuntimeIndexer>(
     Ok(trees)
 }
 
+/// Checks that the database is for the chain and upgrades its schema, recording the genesis hash if the database is new.
+pub fn init_database<R: RuntimeIndexer>(db: &Db) -> Result<(), IndexError> {
+    init_database_version(
+        db,
+        R::get_genesis_hash().as_ref(),
+        schema::SCHEMA_VERSION,
+        schema::MIGRATIONS,
+    )
+}
+
+/// The schema is checked before the genesis hash is written, so that a new database is recorded at the current schema version rather than as a database from before versioning.
+pub(crate) fn init_database_version(
+    db: &Db,
+    genesis_hash: &[u8],
+    version: u16,
+    migrations: &[schema::Migration],
+) -> Result<(), IndexError> {
+    let genesis_hash_db = db.get("genesis_hash")?;
+    if let Some(genesis_hash_db) = &genesis_hash_db {
+        if genesis_hash_db != genesis_hash {
+            return Err(IndexError::WrongGenesisHash(genesis_hash_db.clone()));
+        }
+    }
+    schema::migrate_schema(db, version, migrations)?;
+    if genesis_hash_db.is_none() {
+        db.insert("genesis_hash", genesis_hash)?;
+    }
+    Ok(())
+}
+
 pub fn close_trees<R: RuntimeIndexer>(
     trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
-) -> Result<(), sled::Error> {
+) -> Result<(), StorageError> {
     info!("Closing db.");
     trees.root.flush()?;
     trees.span.flush()?;
     trees.variant.flush()?;
+    trees.failed.flush()?;
     trees.substrate.flush()?;
     Ok(())
 }
 
+/// Delay before the first attempt to reconnect to the node
+pub(crate) const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum delay between attempts to reconnect to the node
+pub(crate) const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Connects to a node and checks that it is on the chain being indexed.
+#[allow(clippy::type_complexity)]
+pub async fn connect<R: RuntimeIndexer>(
+    url: &str,
+) -> Result<
+    (
+        OnlineClient<R::RuntimeConfig>,
+        LegacyRpcMethods<R::RuntimeConfig>,
+    ),
+    IndexError,
+> {
+    let rpc_client = RpcClient::from_url(url).await?;
+    let api = OnlineClient::<R::RuntimeConfig>::from_rpc_client(rpc_client.clone()).await?;
+    let rpc = LegacyRpcMethods::<R::RuntimeConfig>::new(rpc_client);
+    let genesis_hash_api = api.genesis_hash().as_ref().to_vec();
+    if genesis_hash_api != R::get_genesis_hash().as_ref() {
+        return Err(IndexError::WrongGenesisHash(genesis_hash_api));
+    }
+    Ok((api, rpc))
+}
+
+/// Runs the indexer, reconnecting with exponential backoff whenever the connection is lost. After each failed attempt the next endpoint is tried.
+async fn substrate_index_reconnect<R: RuntimeIndexer>(
+    indexer: Indexer<R>,
+    config: IndexerConfig,
+    mut reconnect: ReconnectPolicy,
+    rpc_tx: watch::Sender<LegacyRpcMethods<R::RuntimeConfig>>,
+    mut exit_rx: watch::Receiver<bool>,
+    mut sub_rx: mpsc::UnboundedReceiver<SubscriptionMessage<R::ChainKey>>,
+    mut admin_rx: mpsc::UnboundedReceiver<AdminMessage>,
+) -> Result<(), IndexError> {
+    loop {
+        match substrate_index(
+            &indexer,
+            &config,
+            exit_rx.clone(),
+            &mut sub_rx,
+            &mut admin_rx,
+        )
+        .await
+        {
+            Ok(()) => return Ok(()),
+            Err(
+                err @ (IndexError::Subxt(_)
+                | IndexError::SubscriptionClosed
+                | IndexError::BlockNotFound(_)),
+            ) => error!("Lost connection to {}: {}", reconnect.url(), err),
+            Err(err) => {
+                error!("Indexer failed: {}", err);
+                return Err(err);
+            }
+        }
+        loop {
+            info!(
+                "Reconnecting to {} in {} seconds.",
+                reconnect.url(),
+                reconnect.delay().as_secs()
+            );
+            tokio::select! {
+                _ = exit_rx.changed() => return Ok(()),
+                _ = sleep(reconnect.delay()) => {}
+            }
+            match connect::<R>(reconnect.url()).await {
+                Ok((api, rpc)) => {
+                    info!("Reconnected to: {}", reconnect.url());
+                    indexer.set_client(api, rpc.clone());
+                    let _ = rpc_tx.send(rpc);
+                    reconnect.record_success();
+                    break;
+                }
+                Err(err) => {
+                    error!("Failed to reconnect: {}", err);
+                    reconnect.record_failure();
+                }
+            }
+        }
+    }
+}
+
 /// Starts the indexer. Chain is defined by `R`.
-#[allow(clippy::too_many_arguments)]
-pub async fn start<R: RuntimeIndexer + 'static>(
-    db_path: Option<String>,
-    db_mode: sled::Mode,
-    db_cache_capacity: u64,
-    url: Option<String>,
-    queue_depth: u8,
-    index_variant: bool,
-    port: u16,
-    log_level: LevelFilter,
-) {
-    tracing_subscriber::fmt().with_max_level(log_level).init();
+pub async fn start<R: RuntimeIndexer + 'static>(config: IndexerConfig) {
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level)
+        .init();
     let name = R::get_name();
     info!("Indexing {}", name);
     let genesis_hash_config = R::get_genesis_hash().as_ref().to_vec();
     // Open database.
-    let db_path = match db_path {
+    let db_engine = config.db_engine;
+    let db_path = match &config.db_path {
         Some(db_path) => PathBuf::from(db_path),
         None => match home::home_dir() {
             Some(mut db_path) => {
                 db_path.push(".local/share/acuity-index/");
                 db_path.push(name);
-                db_path.push("db");
+                db_path.push(match db_engine {
+                    DbEngine::Sled => "db",
+                    #[cfg(feature = "redb")]
+                    DbEngine::Redb => "db.redb",
+                });
                 db_path
             }
             None => {
@@ -94,74 +218,103 @@ pub async fn start<R: RuntimeIndexer + 'static>(
         },
     };
     info!("Database path: {}", db_path.display());
-    info!("Database mode: {:?}", db_mode);
+    info!("Database engine: {:?}", db_engine);
+    info!("Database mode: {:?}", config.db_mode);
     info!(
         "Database cache capacity: {}",
-        Byte::from_bytes(db_cache_capacity.into()).get_appropriate_unit(true)
+        Byte::from_bytes(config.db_cache_capacity.into()).get_appropriate_unit(true)
     );
-    let db_config = sled::Config::new()
-        .path(db_path)
-        .mode(db_mode)
-        .cache_capacity(db_cache_capacity);
-    let trees = match open_trees::<R>(db_config) {
+    let db = match db_engine {
+        DbEngine::Sled => Db::sled(
+            sled::Config::new()
+                .path(db_path)
+                .mode(config.db_mode)
+                .cache_capacity(config.db_cache_capacity),
+        ),
+        #[cfg(feature = "redb")]
+        DbEngine::Redb => Db::redb(db_path, config.db_cache_capacity),
+    };
+    let trees = match db.and_then(open_trees::<R>) {
         Ok(trees) => trees,
         Err(_) => {
             error!("Failed to open database.");
             exit(1);
         }
     };
-    let genesis_hash_db = match trees.root.get("genesis_hash").unwrap() {
-        Some(value) => value.to_vec(),
-        //    vector_as_u8_32_array(&value),
-        None => {
-            trees
-                .root
-                .insert("genesis_hash", genesis_hash_config.clone())
-                .unwrap();
-            genesis_hash_config.clone()
+    match init_database::<R>(&trees.root) {
+        Ok(()) => info!("Database schema version: {}", schema::SCHEMA_VERSION),
+        Err(IndexError::WrongGenesisHash(genesis_hash_db)) => {
+            error!("Database has wrong genesis hash.");
+            error!("Correct hash:  0x{}", hex::encode(genesis_hash_config));
+            error!("Database hash: 0x{}", hex::encode(genesis_hash_db));
+            let _ = close_trees::<R>(trees);
+            exit(1);
         }
-    };
-
-    if genesis_hash_db != genesis_hash_config {
-        error!("Database has wrong genesis hash.");
-        error!("Correct hash:  0x{}", hex::encode(genesis_hash_config));
-        error!("Database hash: 0x{}", hex::encode(genesis_hash_db));
-        let _ = close_trees::<R>(trees);
-        exit(1);
-    }
-    // Determine url of Substrate node to connect to.
-    let url = match url {
-        Some(url) => url,
-        None => R::get_default_url().to_owned(),
-    };
-    info!("Connecting to: {}", url);
-    let rpc_client = match RpcClient::from_url(&url).await {
-        Ok(rpc_client) => rpc_client,
-        Err(err) => {
-            error!("Failed to connect: {}", err);
+        Err(IndexError::SchemaTooNew { version, supported }) => {
+            error!(
+                "Database schema version {} is newer than supported version {}.",
+                version, supported
+            );
+            let _ = close_trees::<R>(trees);
+            exit(1);
+        }
+        Err(IndexError::MigrationNotFound { version }) => {
+            error!(
+                "Database schema version {} cannot be migrated to version {}. Delete the database to re-index.",
+                version,
+                schema::SCHEMA_VERSION
+            );
             let _ = close_trees::<R>(trees);
             exit(1);
         }
-    };
-    let api = match OnlineClient::<R::RuntimeConfig>::from_rpc_client(rpc_client.clone()).await {
-        Ok(api) => api,
         Err(err) => {
-            error!("Failed to connect: {}", err);
+            error!("Failed to migrate database: {}", err);
             let _ = close_trees::<R>(trees);
             exit(1);
         }
+    }
+    // Determine urls of Substrate nodes to connect to.
+    let endpoints = match config.endpoints.is_empty() {
+        true => vec![EndpointConfig {
+            url: R::get_default_url().to_owned(),
+            limit: None,
+        }],
+        false => config.endpoints.clone(),
     };
-    let rpc = LegacyRpcMethods::<R::RuntimeConfig>::new(rpc_client);
-
-    let genesis_hash_api = api.genesis_hash().as_ref().to_vec();
-
-    if genesis_hash_api != genesis_hash_config {
-        error!("Chain has wrong genesis hash.");
-        error!("Correct hash: 0x{}", hex::encode(genesis_hash_config));
-        error!("Chain hash:   0x{}", hex::encode(genesis_hash_api));
+    let urls: Vec<String> = endpoints
+        .iter()
+        .map(|endpoint| endpoint.url.clone())
+        .collect();
+    // Follow the head with the first endpoint that can be connected to.
+    let mut client = None;
+    let endpoint_pool = EndpointPool::default();
+    for endpoint in endpoints {
+        info!("Connecting to: {}", endpoint.url);
+        match connect::<R>(&endpoint.url).await {
+            Ok(endpoint_client) => {
+                if client.is_none() {
+                    client = Some((endpoint_pool.len(), endpoint_client.clone()));
+                }
+                endpoint_pool.add(endpoint, Some(endpoint_client));
+            }
+            Err(IndexError::WrongGenesisHash(genesis_hash_api)) => {
+                error!("Chain has wrong genesis hash: {}", endpoint.url);
+                error!("Correct hash: 0x{}", hex::encode(genesis_hash_config));
+                error!("Chain hash:   0x{}", hex::encode(genesis_hash_api));
+                let _ = close_trees::<R>(trees);
+                exit(1);
+            }
+            Err(err) => {
+                error!("Failed to connect to {}: {}", endpoint.url, err);
+                endpoint_pool.add(endpoint, None);
+            }
+        }
+    }
+    let Some((url_index, (api, rpc))) = client else {
+        error!("Failed to connect to any endpoint.");
         let _ = close_trees::<R>(trees);
         exit(1);
-    }
+    };
     // https://docs.rs/signal-hook/0.3.17/signal_hook/#a-complex-signal-handling-with-a-background-thread
     // Make sure double CTRL+C and similar kills.
     let term_now = Arc::new(AtomicBool::new(false));
@@ -178,23 +331,41 @@ pub async fn start<R: RuntimeIndexer + 'static>(
     let (exit_tx, exit_rx) = watch::channel(false);
     // Create the channel for the websockets threads to send subscribe messages to the head thread.
     let (sub_tx, sub_rx) = mpsc::unbounded_channel();
+    // Create the channel for the websockets threads to send admin messages to the head thread.
+    let (admin_tx, admin_rx) = mpsc::unbounded_channel();
+    // Create a watch channel for the indexer thread to send the RPC client to the websockets threads after reconnecting.
+    let (rpc_tx, rpc_rx) = watch::channel(rpc.clone());
+    // Create the metadata cache for the indexer thread to share with the websockets threads.
+    let metadata_map_lock = Arc::new(RwLock::new(AHashMap::new()));
     // Start indexer thread.
-    let substrate_index = spawn(substrate_index::<R>(
+    let indexer = Indexer::<R>::new(
         trees.clone(),
-        api.clone(),
-        rpc.clone(),
-        queue_depth.into(),
-        index_variant,
+        api,
+        rpc,
+        endpoint_pool,
+        config.index_variant,
+        metadata_map_lock.clone(),
+    );
+    let port = config.port;
+    let admin_port = config.admin_port;
+    let substrate_index = spawn(substrate_index_reconnect::<R>(
+        indexer,
+        config,
+        ReconnectPolicy::new(urls, url_index),
+        rpc_tx,
         exit_rx.clone(),
         sub_rx,
+        admin_rx,
     ));
     // Spawn websockets task.
     let websockets_task = spawn(websockets_listen::<R>(
         trees.clone(),
-        rpc,
+        rpc_rx,
+        metadata_map_lock,
         port,
         exit_rx,
         sub_tx,
+        admin_port.map(|admin_port| (admin_port, admin_tx)),
     ));
     // Wait for signal.
     let mut signals = Signals::new(TERM_SIGNALS).unwrap();
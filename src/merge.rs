@@ -0,0 +1,147 @@
+//! Merging of index databases that were built independently for the same chain.
+
+use crate::{
+    prune::carve_span,
+    schema,
+    shared::*,
+    storage::{BatchWriter, DbBatch, Tree},
+    substrate::read_best_span,
+};
+use num_format::{Locale, ToFormattedString};
+use tracing::info;
+use zerocopy::{AsBytes, FromBytes};
+
+/// Span of indexed blocks with the flags it was indexed with
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanRecord {
+    pub span: Span,
+    pub version: u16,
+    pub index_variant: bool,
+}
+
+/// Reads all the spans in a span tree.
+pub fn read_span_records(span_db: &Tree) -> Result<Vec<SpanRecord>, IndexError> {
+    let mut records = Vec::new();
+    for item in span_db.iter() {
+        let (key, value) = item?;
+        let value = SpanDbValue::read_from(value.as_slice()).unwrap();
+        records.push(SpanRecord {
+            span: Span {
+                start: value.start.into(),
+                end: u32::from_be_bytes(key.as_slice().try_into().unwrap()),
+            },
+            version: value.version.into(),
+            index_variant: value.index_variant == 1,
+        });
+    }
+    Ok(records)
+}
+
+/// Coalesces overlapping and contiguous spans that were indexed with the same flags.
+fn coalesce_matching_spans(records: Vec<SpanRecord>) -> Vec<SpanRecord> {
+    let mut coalesced: Vec<SpanRecord> = Vec::new();
+    for record in records {
+        match coalesced.last_mut() {
+            // Has the last span indexed all the blocks before this span with the same flags?
+            Some(last)
+                if last.version == record.version
+                    && last.index_variant == record.index_variant
+                    && record.span.start <= last.span.end.saturating_add(1) =>
+            {
+                last.span.end = last.span.end.max(record.span.end);
+            }
+            _ => coalesced.push(record),
+        }
+    }
+    coalesced
+}
+
+/// Coalesces overlapping and contiguous spans that were indexed with the same flags. Where spans indexed with and without event variants overlap, the blocks have been indexed with event variants, so the spans without them are cut back.
+pub fn coalesce_spans(mut records: Vec<SpanRecord>) -> Vec<SpanRecord> {
+    records.sort_by_key(|record| record.span.start);
+    let (with_variants, without_variants) =
+        records.into_iter().partition(|record| record.index_variant);
+    let mut coalesced = coalesce_matching_spans(with_variants);
+    let mut carved = coalesce_matching_spans(without_variants);
+    for record in &coalesced {
+        carved = carved
+            .into_iter()
+            .flat_map(|carved_record| {
+                carve_span(&carved_record.span, &record.span)
+                    .into_iter()
+                    .map(move |span| SpanRecord {
+                        span,
+                        ..carved_record.clone()
+                    })
+            })
+            .collect();
+    }
+    coalesced.append(&mut carved);
+    coalesced.sort_by_key(|record| record.span.start);
+    coalesced
+}
+
+/// Merges the database `source` into the database `trees`. Both databases must be for the same chain, all their spans must have been indexed by the same indexer version, and neither may have unfinalized best blocks.
+pub fn merge_databases<CT>(trees: &Trees<CT>, source: &Trees<CT>) -> Result<u64, IndexError> {
+    let genesis_hash = trees.root.get("genesis_hash")?;
+    if genesis_hash.is_none() || genesis_hash != source.root.get("genesis_hash")? {
+        return Err(IndexError::MergeConflict("genesis hash"));
+    }
+    if schema::read_schema_version(&trees.root)? != schema::read_schema_version(&source.root)? {
+        return Err(IndexError::MergeConflict("schema version"));
+    }
+    // The keys of best blocks that were not finalized are only deleted when the indexer is started again.
+    if read_best_span(&trees.root)?.is_some() || read_best_span(&source.root)?.is_some() {
+        return Err(IndexError::MergeConflict("unfinalized best blocks"));
+    }
+    let mut records = read_span_records(&trees.span)?;
+    records.append(&mut read_span_records(&source.span)?);
+    if records
+        .iter()
+        .any(|record| record.version != records[0].version)
+    {
+        return Err(IndexError::MergeConflict("indexer version"));
+    }
+    // Union the key trees.
+    let mut count = 0;
+    for name in source.root.tree_names()? {
+        if name == b"span" {
+            continue;
+        }
+        let mut writer = BatchWriter::new(trees.root.open_tree(&name)?);
+        for item in source.root.open_tree(&name)?.iter() {
+            let (key, value) = item?;
+            writer.insert(key, value)?;
+            count += 1;
+        }
+        writer.finish()?;
+    }
+    // Replace the spans once all the keys have been merged.
+    let mut batch = DbBatch::default();
+    for item in trees.span.iter() {
+        let (key, _) = item?;
+        batch.remove(&trees.span, key);
+    }
+    let records = coalesce_spans(records);
+    for record in &records {
+        info!(
+            "📚 Merged span of indexed blocks from #{} to #{}.",
+            record.span.start.to_formatted_string(&Locale::en),
+            record.span.end.to_formatted_string(&Locale::en)
+        );
+        let value = SpanDbValue {
+            start: record.span.start.into(),
+            version: record.version.into(),
+            index_variant: record.index_variant.into(),
+        };
+        batch.insert(&trees.span, record.span.end.to_be_bytes(), value.as_bytes());
+    }
+    trees.root.apply_batch(batch)?;
+    // Blocks that failed in one database may have been indexed in the other.
+    for record in &records {
+        remove_failed_blocks(&trees.failed, &record.span)?;
+    }
+    trees.root.flush()?;
+    info!("Merged {} keys.", count.to_formatted_string(&Locale::en));
+    Ok(count)
+}
@@ -0,0 +1,165 @@
+//! Pruning of index entries outside a retention window.
+
+use crate::{
+    merge::read_span_records,
+    shared::*,
+    storage::{Batch, BatchWriter, Db, Tree},
+};
+use num_format::{Locale, ToFormattedString};
+use std::collections::BTreeMap;
+use tokio::task::{self, JoinHandle};
+use tracing::{error, info};
+use zerocopy::AsBytes;
+
+/// Number of blocks indexed at the head between each pruning of the database
+pub const PRUNE_INTERVAL: u32 = 1000;
+
+/// Lowest block that is retained when keeping the last `retention` blocks up to `head`.
+pub fn retention_min_block(head: u32, retention: u32) -> u32 {
+    (head + 1).saturating_sub(retention)
+}
+
+/// Reads the block number of a key. All key formats end with the block number and event index.
+pub(crate) fn key_block_number(key: &[u8]) -> Option<u32> {
+    let end = key.len().checked_sub(2)?;
+    let start = end.checked_sub(4)?;
+    Some(u32::from_be_bytes(key[start..end].try_into().unwrap()))
+}
+
+/// Removes a range of blocks from all the span records, keeping the parts of each span before and after the range.
+pub fn carve_span_records(span_db: &Tree, range: &Span) -> Result<(), IndexError> {
+    let mut batch = Batch::default();
+    for record in read_span_records(span_db)? {
+        if record.span.end < range.start || record.span.start > range.end {
+            continue;
+        }
+        batch.remove(record.span.end.to_be_bytes());
+        for span in carve_span(&record.span, range) {
+            let value = SpanDbValue {
+                start: span.start.into(),
+                version: record.version.into(),
+                index_variant: record.index_variant.into(),
+            };
+            batch.insert(span.end.to_be_bytes(), value.as_bytes());
+        }
+    }
+    span_db.apply_batch(batch)?;
+    Ok(())
+}
+
+/// Deletes all keys for a range of blocks from every tree except the span and failed trees, returning the number of keys deleted.
+pub fn delete_keys(db: &Db, range: &Span) -> Result<u64, IndexError> {
+    let mut count = 0;
+    for name in db.tree_names()? {
+        if name == b"span" || name == b"failed" {
+            continue;
+        }
+        let tree = db.open_tree(&name)?;
+        let mut writer = BatchWriter::new(tree.clone());
+        for item in tree.iter() {
+            let (key, _) = item?;
+            if key_block_number(&key).is_some_and(|block_number| {
+                range.start <= block_number && block_number <= range.end
+            }) {
+                writer.remove(key)?;
+                count += 1;
+            }
+        }
+        writer.finish()?;
+    }
+    Ok(count)
+}
+
+/// Deletes all keys for blocks before `min_block` from the database, returning the number of keys deleted. Spans and failed blocks are truncated first so they never include pruned blocks.
+pub fn prune_trees<CT>(trees: &Trees<CT>, min_block: u32) -> Result<u64, IndexError> {
+    let Some(end) = min_block.checked_sub(1) else {
+        return Ok(0);
+    };
+    let range = Span { start: 0, end };
+    carve_span_records(&trees.span, &range)?;
+    remove_failed_blocks(&trees.failed, &range)?;
+    let count = delete_keys(&trees.root, &range)?;
+    info!(
+        "🗑️  Pruned {} keys before #{}",
+        count.to_formatted_string(&Locale::en),
+        min_block.to_formatted_string(&Locale::en)
+    );
+    Ok(count)
+}
+
+/// Truncates the spans and failed blocks before `min_block`, then deletes the keys before it on a blocking thread so that indexing carries on while every tree is swept. No keys before `min_block` may be written while the task is running.
+pub fn spawn_prune_trees<CT>(
+    trees: &Trees<CT>,
+    min_block: u32,
+) -> Result<Option<JoinHandle<()>>, IndexError> {
+    let Some(end) = min_block.checked_sub(1) else {
+        return Ok(None);
+    };
+    let range = Span { start: 0, end };
+    carve_span_records(&trees.span, &range)?;
+    remove_failed_blocks(&trees.failed, &range)?;
+    let db = trees.root.clone();
+    Ok(Some(task::spawn_blocking(move || {
+        match delete_keys(&db, &range) {
+            Ok(count) => info!(
+                "🗑️  Pruned {} keys before #{}",
+                count.to_formatted_string(&Locale::en),
+                min_block.to_formatted_string(&Locale::en)
+            ),
+            Err(err) => error!("🗑️  Pruning failed: {}", err),
+        }
+    })))
+}
+
+/// Returns the parts of a span before and after a range of blocks.
+pub fn carve_span(span: &Span, range: &Span) -> Vec<Span> {
+    let mut spans = Vec::new();
+    if span.start < range.start {
+        spans.push(Span {
+            start: span.start,
+            end: span.end.min(range.start - 1),
+        });
+    }
+    if span.end > range.end {
+        spans.push(Span {
+            start: span.start.max(range.end + 1),
+            end: span.end,
+        });
+    }
+    spans
+}
+
+/// Removes a range of blocks from in-memory spans and orphans.
+pub fn carve_spans(spans: &mut Vec<Span>, orphans: &mut BTreeMap<u32, u32>, range: &Span) {
+    *spans = spans
+        .iter()
+        .flat_map(|span| carve_span(span, range))
+        .collect();
+    *orphans = orphans
+        .iter()
+        .flat_map(|(end, start)| {
+            carve_span(
+                &Span {
+                    start: *start,
+                    end: *end,
+                },
+                range,
+            )
+        })
+        .map(|span| (span.end, span.start))
+        .collect();
+}
+
+/// Truncates in-memory spans and orphans so they do not include blocks before `min_block`.
+pub fn prune_spans(
+    spans: &mut Vec<Span>,
+    current_span: &mut Span,
+    orphans: &mut BTreeMap<u32, u32>,
+    min_block: u32,
+) {
+    let Some(end) = min_block.checked_sub(1) else {
+        return;
+    };
+    carve_spans(spans, orphans, &Span { start: 0, end });
+    current_span.start = current_span.start.max(min_block);
+}
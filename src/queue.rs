@@ -0,0 +1,107 @@
+//! Adaptive queue depth for batch indexing.
+
+use std::time::Duration;
+
+/// Latency and errors of batch block requests since they were last taken
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BatchStats {
+    /// Number of requests, including failed attempts
+    pub requests: u32,
+    /// Number of failed attempts
+    pub errors: u32,
+    /// Total latency of the successful requests
+    pub latency: Duration,
+}
+
+impl BatchStats {
+    pub fn record(&mut self, latency: Duration, success: bool) {
+        self.requests += 1;
+        match success {
+            true => self.latency += latency,
+            false => self.errors += 1,
+        }
+    }
+
+    /// Mean latency of the successful requests.
+    pub fn mean_latency(&self) -> Option<Duration> {
+        let successes = self.requests - self.errors;
+        (successes != 0).then(|| self.latency / successes)
+    }
+}
+
+/// Grows or shrinks the number of batch blocks indexed at the same time.
+///
+/// The depth doubles until the first sign of overload, then grows by one block while throughput keeps up. It halves when requests fail, e.g. because of rate limiting, and shrinks by one block when latency rises or a larger depth did not increase throughput.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueDepthController {
+    min: u32,
+    max: u32,
+    depth: u32,
+    slow_start: bool,
+    grew: bool,
+    /// Blocks per second in the previous interval
+    throughput: u128,
+    /// Latency of an unloaded node, drifting up towards recent latency
+    base_latency: Option<Duration>,
+}
+
+impl QueueDepthController {
+    pub fn new(min: u32, max: u32) -> Self {
+        let min = min.max(1);
+        QueueDepthController {
+            min,
+            max: max.max(min),
+            depth: min,
+            slow_start: true,
+            grew: false,
+            throughput: 0,
+            base_latency: None,
+        }
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Adjusts the depth with the stats of an interval and returns it. `throughput` is in blocks per second.
+    pub fn update(&mut self, stats: BatchStats, throughput: u128) -> u32 {
+        if stats.requests == 0 {
+            return self.depth;
+        }
+        let latency = stats.mean_latency();
+        let grew = self.grew;
+        self.grew = false;
+        // Requests are queueing at the node or a larger depth did not help.
+        let is_overloaded = latency
+            .zip(self.base_latency)
+            .is_some_and(|(latency, base_latency)| latency > base_latency * 2)
+            || (grew && throughput < self.throughput * 9 / 10);
+        if stats.errors != 0 {
+            self.shrink(self.depth / 2);
+        } else if is_overloaded {
+            self.shrink(self.depth - 1);
+        } else if self.depth < self.max {
+            self.depth = match self.slow_start {
+                true => self.depth * 2,
+                false => self.depth + 1,
+            }
+            .min(self.max);
+            self.grew = true;
+        }
+        if let Some(latency) = latency {
+            self.base_latency = Some(match self.base_latency {
+                Some(base_latency) if base_latency < latency => {
+                    base_latency + (latency - base_latency) / 8
+                }
+                _ => latency,
+            });
+        }
+        self.throughput = throughput;
+        self.depth
+    }
+
+    fn shrink(&mut self, depth: u32) {
+        self.depth = depth.max(self.min);
+        self.slow_start = false;
+    }
+}
@@ -0,0 +1,76 @@
+//! Versioning of the on-disk database layout.
+//!
+//! The layout of `Bytes32Key`, `U32Key`, `VariantKey` and `SpanDbValue` is recorded as a schema version in the root tree. Whenever the layout changes, `SCHEMA_VERSION` must be incremented and a migration from the previous version added to `MIGRATIONS`.
+
+use crate::{shared::IndexError, storage::Db};
+use tracing::info;
+
+/// Current version of the database layout
+pub const SCHEMA_VERSION: u16 = 1;
+
+/// Version of databases created before the schema version was recorded
+const LEGACY_SCHEMA_VERSION: u16 = 1;
+
+/// Upgrades a database from one schema version to the next
+pub struct Migration {
+    pub from: u16,
+    pub description: &'static str,
+    pub migrate: fn(&Db) -> Result<(), IndexError>,
+}
+
+/// All the migrations, one for each schema version before `SCHEMA_VERSION`
+pub const MIGRATIONS: &[Migration] = &[];
+
+/// Reads the schema version of the database.
+pub fn read_schema_version(db: &Db) -> Result<Option<u16>, IndexError> {
+    Ok(db
+        .get("schema_version")?
+        .map(|value| u16::from_be_bytes(value.as_slice().try_into().unwrap())))
+}
+
+fn write_schema_version(db: &Db, version: u16) -> Result<(), IndexError> {
+    db.insert("schema_version", version.to_be_bytes())?;
+    Ok(())
+}
+
+/// Checks the schema version of the database, upgrading it with the migrations if it is older than `version`.
+pub fn migrate_schema(db: &Db, version: u16, migrations: &[Migration]) -> Result<(), IndexError> {
+    let mut db_version = match read_schema_version(db)? {
+        Some(db_version) => db_version,
+        // A database without any genesis hash is new.
+        None => match db.get("genesis_hash")? {
+            Some(_) => LEGACY_SCHEMA_VERSION,
+            None => version,
+        },
+    };
+    if db_version > version {
+        return Err(IndexError::SchemaTooNew {
+            version: db_version,
+            supported: version,
+        });
+    }
+    while db_version < version {
+        let migration = migrations
+            .iter()
+            .find(|migration| migration.from == db_version)
+            .ok_or(IndexError::MigrationNotFound {
+                version: db_version,
+            })?;
+        info!(
+            "Migrating database from schema version {} to {}: {}",
+            db_version,
+            db_version + 1,
+            migration.description
+        );
+        (migration.migrate)(db)?;
+        db_version += 1;
+        write_schema_version(db, db_version)?;
+        db.flush()?;
+    }
+    write_schema_version(db, db_version)
+}
+
+/// Checks the schema version of the database against the version of the library, upgrading it if necessary.
+pub fn check_schema(db: &Db) -> Result<(), IndexError> {
+    migrate_schema(db, SCHEMA_VERSION, MIGRATIONS)
+}
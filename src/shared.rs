@@ -1,10 +1,14 @@
+use crate::storage::{Batch, Db, DbBatch, DbEngine, StorageError, Tree};
+use ahash::AHashMap;
 use byteorder::BigEndian;
 use serde::{Deserialize, Serialize};
-use sled::{Db, Tree};
 use std::fmt;
 use std::hash::Hash;
+use std::time::Duration;
+use subxt::{error::RpcError, metadata::Metadata};
 use tokio::sync::mpsc::UnboundedSender;
 use tokio_tungstenite::tungstenite;
+use tracing_subscriber::filter::LevelFilter;
 use zerocopy::{
     byteorder::{U16, U32},
     AsBytes,
@@ -15,7 +19,7 @@ use zerocopy_derive::{AsBytes, FromBytes, FromZeroes, Unaligned};
 #[derive(thiserror::Error, Debug)]
 pub enum IndexError {
     #[error("database error")]
-    Sled(#[from] sled::Error),
+    Storage(#[from] StorageError),
     #[error("connection error")]
     Subxt(#[from] subxt::Error),
     #[error("connection error")]
@@ -24,10 +28,56 @@ pub enum IndexError {
     Hex(#[from] hex::FromHexError),
     #[error("parse error")]
     ParseError,
+    #[error("parse error")]
+    Json(#[from] serde_json::Error),
+    #[error("unknown key type")]
+    UnknownKeyType(serde_json::Error),
+    #[error("limit exceeded")]
+    LimitExceeded { limit: usize, max: usize },
+    #[error("variant not found")]
+    VariantNotFound { pallet: String, event: String },
+    #[error("database schema version is newer than supported")]
+    SchemaTooNew { version: u16, supported: u16 },
+    #[error("no migration from database schema version")]
+    MigrationNotFound { version: u16 },
+    #[error("invalid snapshot")]
+    InvalidSnapshot(&'static str),
+    #[error("databases cannot be merged")]
+    MergeConflict(&'static str),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
     #[error("connection error")]
     BlockNotFound(u32),
+    #[error("connection error")]
+    SubscriptionClosed,
+    #[error("chain has wrong genesis hash")]
+    WrongGenesisHash(Vec<u8>),
+    #[error("invalid block range")]
+    InvalidBlockRange,
+    #[error("admin requests are disabled")]
+    AdminDisabled,
 }
 
+/// JSON-RPC error code of the node's state methods when the client fails, such as when the state of the block has been pruned
+const STATE_CLIENT_ERROR_CODE: i32 = 4003;
+
+impl IndexError {
+    /// Whether the node does not have the block or its state, either because it has not synced the block yet or has pruned it. Retrying on the same node will not help.
+    pub fn is_block_unavailable(&self) -> bool {
+        match self {
+            IndexError::BlockNotFound(_) => true,
+            IndexError::Subxt(subxt::Error::Rpc(RpcError::ClientError(error))) => matches!(
+                error.downcast_ref::<jsonrpsee_core::ClientError>(),
+                Some(jsonrpsee_core::ClientError::Call(error)) if error.code() == STATE_CLIENT_ERROR_CODE
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Metadata for each spec version, shared between the indexer and WebSocket threads
+pub type MetadataMap = AHashMap<u32, Metadata>;
+
 /// Indexer for a specific chain
 pub trait RuntimeIndexer {
     type RuntimeConfig: subxt::Config;
@@ -57,10 +107,10 @@ pub trait RuntimeIndexer {
 }
 
 pub trait IndexTrees {
-    fn open(db: &Db) -> Result<Self, sled::Error>
+    fn open(db: &Db) -> Result<Self, StorageError>
     where
         Self: Sized;
-    fn flush(&self) -> Result<(), sled::Error>;
+    fn flush(&self) -> Result<(), StorageError>;
 }
 
 /// Database trees for built-in Substrate keys
@@ -82,7 +132,7 @@ pub struct SubstrateTrees {
 }
 
 impl SubstrateTrees {
-    pub fn open(db: &Db) -> Result<Self, sled::Error> {
+    pub fn open(db: &Db) -> Result<Self, StorageError> {
         Ok(SubstrateTrees {
             account_id: db.open_tree(b"account_id")?,
             account_index: db.open_tree(b"account_index")?,
@@ -100,7 +150,7 @@ impl SubstrateTrees {
         })
     }
 
-    pub fn flush(&self) -> Result<(), sled::Error> {
+    pub fn flush(&self) -> Result<(), StorageError> {
         self.account_id.flush()?;
         self.account_index.flush()?;
         self.bounty_index.flush()?;
@@ -121,9 +171,10 @@ impl SubstrateTrees {
 /// Database trees for the indexer
 #[derive(Clone)]
 pub struct Trees<CT> {
-    pub root: sled::Db,
+    pub root: Db,
     pub span: Tree,
     pub variant: Tree,
+    pub failed: Tree,
     pub substrate: SubstrateTrees,
     pub chain: CT,
 }
@@ -239,9 +290,10 @@ impl SubstrateKey {
     pub fn write_db_key(
         &self,
         trees: &SubstrateTrees,
+        batch: &mut DbBatch,
         block_number: u32,
         event_index: u16,
-    ) -> Result<(), sled::Error> {
+    ) {
         let block_number = block_number.into();
         let event_index = event_index.into();
         match self {
@@ -251,7 +303,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.account_id.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.account_id, key.as_bytes(), [])
             }
             SubstrateKey::AccountIndex(account_index) => {
                 let key = U32Key {
@@ -259,7 +311,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.account_index.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.account_index, key.as_bytes(), [])
             }
             SubstrateKey::BountyIndex(bounty_index) => {
                 let key = U32Key {
@@ -267,7 +319,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.bounty_index.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.bounty_index, key.as_bytes(), [])
             }
             SubstrateKey::EraIndex(era_index) => {
                 let key = U32Key {
@@ -275,7 +327,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.era_index.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.era_index, key.as_bytes(), [])
             }
             SubstrateKey::MessageId(message_id) => {
                 let key = Bytes32Key {
@@ -283,7 +335,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.message_id.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.message_id, key.as_bytes(), [])
             }
             SubstrateKey::PoolId(pool_id) => {
                 let key = U32Key {
@@ -291,7 +343,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.pool_id.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.pool_id, key.as_bytes(), [])
             }
             SubstrateKey::PreimageHash(preimage_hash) => {
                 let key = Bytes32Key {
@@ -299,7 +351,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.preimage_hash.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.preimage_hash, key.as_bytes(), [])
             }
             SubstrateKey::ProposalHash(proposal_hash) => {
                 let key = Bytes32Key {
@@ -307,7 +359,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.proposal_hash.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.proposal_hash, key.as_bytes(), [])
             }
             SubstrateKey::ProposalIndex(proposal_index) => {
                 let key = U32Key {
@@ -315,7 +367,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.proposal_index.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.proposal_index, key.as_bytes(), [])
             }
             SubstrateKey::RefIndex(ref_index) => {
                 let key = U32Key {
@@ -323,7 +375,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.ref_index.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.ref_index, key.as_bytes(), [])
             }
             SubstrateKey::RegistrarIndex(registrar_index) => {
                 let key = U32Key {
@@ -331,7 +383,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.registrar_index.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.registrar_index, key.as_bytes(), [])
             }
             SubstrateKey::SessionIndex(session_index) => {
                 let key = U32Key {
@@ -339,7 +391,7 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.session_index.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.session_index, key.as_bytes(), [])
             }
             SubstrateKey::TipHash(tip_hash) => {
                 let key = Bytes32Key {
@@ -347,24 +399,29 @@ impl SubstrateKey {
                     block_number,
                     event_index,
                 };
-                trees.tip_hash.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.tip_hash, key.as_bytes(), [])
             }
         };
-        Ok(())
     }
 }
 
 pub trait IndexKey {
     type ChainTrees: IndexTrees + Send + Sync + Clone;
 
+    /// Adds the database key for an event to the batch being written for its block.
     fn write_db_key(
         &self,
         trees: &Self::ChainTrees,
+        batch: &mut DbBatch,
         block_number: u32,
         event_index: u16,
-    ) -> Result<(), sled::Error>;
+    );
 
-    fn get_key_events(&self, trees: &Self::ChainTrees) -> Vec<Event>;
+    fn get_key_events(
+        &self,
+        trees: &Self::ChainTrees,
+        query: &EventQuery,
+    ) -> (Vec<Event>, Option<Event>);
 }
 
 /// All the key types for the chain
@@ -380,9 +437,10 @@ impl<CK: IndexKey> Key<CK> {
     pub fn write_db_key(
         &self,
         trees: &Trees<CK::ChainTrees>,
+        batch: &mut DbBatch,
         block_number: u32,
         event_index: u16,
-    ) -> Result<(), sled::Error> {
+    ) {
         match self {
             Key::Variant(pallet_index, variant_index) => {
                 let key = VariantKey {
@@ -391,16 +449,83 @@ impl<CK: IndexKey> Key<CK> {
                     block_number: block_number.into(),
                     event_index: event_index.into(),
                 };
-                trees.variant.insert(key.as_bytes(), &[])?;
+                batch.insert(&trees.variant, key.as_bytes(), []);
             }
             Key::Substrate(substrate_key) => {
-                substrate_key.write_db_key(&trees.substrate, block_number, event_index)?;
+                substrate_key.write_db_key(&trees.substrate, batch, block_number, event_index);
             }
             Key::Chain(chain_key) => {
-                chain_key.write_db_key(&trees.chain, block_number, event_index)?;
+                chain_key.write_db_key(&trees.chain, batch, block_number, event_index);
             }
         };
-        Ok(())
+    }
+}
+
+/// Key in a request, where an event variant can also be given by name
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+#[serde(tag = "type", content = "value")]
+pub enum RequestKey<CK: IndexKey> {
+    Variant(u8, u8),
+    Substrate(SubstrateKey),
+    Chain(CK),
+    /// Event variant by name, in any runtime version
+    VariantName {
+        pallet: String,
+        event: String,
+    },
+}
+
+impl<CK: IndexKey> From<Key<CK>> for RequestKey<CK> {
+    fn from(key: Key<CK>) -> Self {
+        match key {
+            Key::Variant(pallet_index, variant_index) => {
+                RequestKey::Variant(pallet_index, variant_index)
+            }
+            Key::Substrate(key) => RequestKey::Substrate(key),
+            Key::Chain(key) => RequestKey::Chain(key),
+        }
+    }
+}
+
+impl<CK: IndexKey> From<RequestKey<CK>> for KeyExpression<CK> {
+    fn from(key: RequestKey<CK>) -> Self {
+        match key {
+            RequestKey::Variant(pallet_index, variant_index) => {
+                KeyExpression::Key(Key::Variant(pallet_index, variant_index))
+            }
+            RequestKey::Substrate(key) => KeyExpression::Key(Key::Substrate(key)),
+            RequestKey::Chain(key) => KeyExpression::Key(Key::Chain(key)),
+            RequestKey::VariantName { pallet, event } => {
+                KeyExpression::VariantName { pallet, event }
+            }
+        }
+    }
+}
+
+/// Boolean expression over keys
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
+#[serde(tag = "type", content = "value")]
+pub enum KeyExpression<CK: IndexKey> {
+    Key(Key<CK>),
+    /// Event variant by name, in any runtime version
+    VariantName {
+        pallet: String,
+        event: String,
+    },
+    And(Vec<KeyExpression<CK>>),
+    Or(Vec<KeyExpression<CK>>),
+}
+
+impl<CK: IndexKey> KeyExpression<CK> {
+    /// Keys in the expression. Variant names must be resolved first.
+    pub fn keys(&self) -> Vec<&Key<CK>> {
+        match self {
+            KeyExpression::Key(key) => vec![key],
+            KeyExpression::VariantName { .. } => Vec::new(),
+            KeyExpression::And(operands) | KeyExpression::Or(operands) => {
+                operands.iter().flat_map(KeyExpression::keys).collect()
+            }
+        }
     }
 }
 
@@ -412,14 +537,54 @@ pub enum RequestMessage<CK: IndexKey> {
     SubscribeStatus,
     UnsubscribeStatus,
     Variants,
-    GetEvents { key: Key<CK> },
-    SubscribeEvents { key: Key<CK> },
-    UnsubscribeEvents { key: Key<CK> },
+    GetEvents {
+        key: RequestKey<CK>,
+        #[serde(flatten)]
+        query: EventQuery,
+    },
+    GetExpressionEvents {
+        expression: KeyExpression<CK>,
+        #[serde(flatten)]
+        query: EventQuery,
+    },
+    GetEventsBatch {
+        keys: Vec<Key<CK>>,
+        #[serde(default)]
+        merge: bool,
+        #[serde(flatten)]
+        query: EventQuery,
+    },
+    #[serde(rename_all = "camelCase")]
+    CountEvents {
+        key: RequestKey<CK>,
+        from_block: Option<u32>,
+        to_block: Option<u32>,
+    },
+    SubscribeEvents {
+        key: RequestKey<CK>,
+        since: Option<Event>,
+    },
+    UnsubscribeEvents {
+        key: RequestKey<CK>,
+    },
     SizeOnDisk,
+    #[serde(rename_all = "camelCase")]
+    Reindex {
+        from_block: u32,
+        to_block: u32,
+    },
+}
+
+/// JSON request message with an optional client-supplied id
+#[derive(Deserialize, Debug, Clone)]
+pub struct Request<CK: IndexKey> {
+    pub id: Option<u32>,
+    #[serde(flatten)]
+    pub msg: RequestMessage<CK>,
 }
 
 /// Identifies an event by block number and event index
-#[derive(Serialize, Debug, Clone, Deserialize, PartialEq)]
+#[derive(Serialize, Debug, Clone, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(rename_all = "camelCase")]
 pub struct Event {
     pub block_number: u32,
@@ -436,6 +601,42 @@ impl fmt::Display for Event {
     }
 }
 
+/// Default number of events returned by an event query
+pub const DEFAULT_EVENTS_LIMIT: u16 = 100;
+
+/// Maximum number of events returned by an event query
+pub const MAX_EVENTS_LIMIT: u16 = 1000;
+
+/// Maximum number of events replayed when subscribing to a key with `since`
+pub const MAX_REPLAY_EVENTS: usize = 10_000;
+
+/// Maximum number of keys in a batch event query
+pub const MAX_BATCH_KEYS: usize = 100;
+
+/// Order of events returned by an event query
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Order {
+    /// Newest first
+    #[default]
+    Descending,
+    /// Oldest first
+    Ascending,
+}
+
+/// Pagination, block range and order parameters for event queries
+///
+/// `cursor` is the last event of the previous page. `from_block` and `to_block` are inclusive.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EventQuery {
+    pub cursor: Option<Event>,
+    pub limit: Option<u16>,
+    pub from_block: Option<u32>,
+    pub to_block: Option<u32>,
+    #[serde(default)]
+    pub order: Order,
+}
+
 /// Index and name of an event type
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct EventMeta {
@@ -467,41 +668,273 @@ pub struct Span {
     pub end: u32,
 }
 
+/// Range of blocks the indexer is configured to index
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexRange {
+    /// Lowest block to index
+    pub start: u32,
+    /// Highest block to index, or `None` to keep following the finalized head
+    pub end: Option<u32>,
+    /// Whether to index blocks before the finalized head
+    pub backfill: bool,
+}
+
+impl Default for IndexRange {
+    fn default() -> Self {
+        IndexRange {
+            start: 0,
+            end: None,
+            backfill: true,
+        }
+    }
+}
+
+impl IndexRange {
+    /// Reads the range the database is being indexed with.
+    pub fn read(db: &Db) -> Result<Option<Self>, IndexError> {
+        Ok(match db.get("index_range")? {
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None,
+        })
+    }
+
+    /// Records the range the database is being indexed with.
+    pub fn write(&self, db: &Db) -> Result<(), IndexError> {
+        db.insert("index_range", serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+}
+
+/// Retrying of batch blocks that fail to be indexed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryConfig {
+    /// Number of times a block is retried before it is recorded as failed
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each further retry
+    pub backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Number of batch blocks indexed at the same time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueDepth {
+    Fixed(u32),
+    /// Adjusted within the bounds according to the latency, error rate and throughput of the batch
+    Adaptive {
+        min: u32,
+        max: u32,
+    },
+}
+
+impl fmt::Display for QueueDepth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueDepth::Fixed(depth) => write!(f, "{}", depth),
+            QueueDepth::Adaptive { min, max } => write!(f, "adaptive, {} to {}", min, max),
+        }
+    }
+}
+
+/// RPC endpoint of a node to index from
+#[derive(Debug, Clone, PartialEq)]
+pub struct EndpointConfig {
+    pub url: String,
+    /// Maximum number of batch blocks to index from the endpoint at the same time
+    pub limit: Option<u32>,
+}
+
+/// Configuration of the indexer
+#[derive(Debug, Clone)]
+pub struct IndexerConfig {
+    /// Path of the database, or a directory for the chain in the home directory if `None`
+    pub db_path: Option<String>,
+    pub db_engine: DbEngine,
+    pub db_mode: sled::Mode,
+    /// Cache capacity of the database in bytes
+    pub db_cache_capacity: u64,
+    /// Endpoints that batch blocks are spread across, or the default endpoint of the chain if empty
+    pub endpoints: Vec<EndpointConfig>,
+    pub queue_depth: QueueDepth,
+    pub index_variant: bool,
+    pub index_best: bool,
+    pub index_range: IndexRange,
+    /// Number of blocks to keep up to the finalized head, or all blocks if `None`
+    pub retention: Option<u32>,
+    pub retry: RetryConfig,
+    /// Port of the public WebSocket listener
+    pub port: u16,
+    /// Port of the WebSocket listener for admin requests on 127.0.0.1, or no admin requests if `None`
+    pub admin_port: Option<u16>,
+    pub log_level: LevelFilter,
+}
+
+impl Default for IndexerConfig {
+    fn default() -> Self {
+        IndexerConfig {
+            db_path: None,
+            db_engine: DbEngine::default(),
+            db_mode: sled::Mode::LowSpace,
+            db_cache_capacity: 1024 * 1024 * 1024,
+            endpoints: Vec::new(),
+            queue_depth: QueueDepth::Fixed(1),
+            index_variant: false,
+            index_best: false,
+            index_range: IndexRange::default(),
+            retention: None,
+            retry: RetryConfig::default(),
+            port: 8172,
+            admin_port: None,
+            log_level: LevelFilter::INFO,
+        }
+    }
+}
+
+/// Maximum number of failed blocks listed in a status response
+pub const MAX_FAILED_BLOCKS: usize = 100;
+
+/// Reads the first `limit` batch blocks that could not be indexed. Each failed block is a key in the failed tree.
+pub fn read_failed_blocks(failed_db: &Tree, limit: usize) -> Result<Vec<u32>, IndexError> {
+    let mut failed_blocks = Vec::new();
+    for item in failed_db.iter().take(limit) {
+        let (key, _) = item?;
+        failed_blocks.push(u32::from_be_bytes(key.as_slice().try_into().unwrap()));
+    }
+    Ok(failed_blocks)
+}
+
+/// Removes the failed blocks in a range of blocks.
+pub fn remove_failed_blocks(failed_db: &Tree, range: &Span) -> Result<(), IndexError> {
+    let mut batch = Batch::default();
+    for item in failed_db.range(range.start.to_be_bytes()..=range.end.to_be_bytes()) {
+        let (key, _) = item?;
+        batch.remove(key);
+    }
+    failed_db.apply_batch(batch)?;
+    Ok(())
+}
+
 impl fmt::Display for Span {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "start: {}, end: {}", self.start, self.end)
     }
 }
 
+/// Events for one of the keys in a batch event query
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyEvents<CK: IndexKey> {
+    pub key: Key<CK>,
+    pub events: Vec<Event>,
+    pub next_cursor: Option<Event>,
+}
+
 /// JSON response messages
 #[derive(Serialize, Debug, Clone)]
 #[serde(tag = "type", content = "data")]
 #[serde(rename_all = "camelCase")]
 pub enum ResponseMessage<CK: IndexKey> {
-    Status(Vec<Span>),
+    #[serde(rename_all = "camelCase")]
+    Status {
+        spans: Vec<Span>,
+        target: Option<IndexRange>,
+        /// The first failed blocks, up to `MAX_FAILED_BLOCKS`
+        failed: Vec<u32>,
+        failed_count: u64,
+    },
     Variants(Vec<PalletMeta>),
-    Events { key: Key<CK>, events: Vec<Event> },
+    #[serde(rename_all = "camelCase")]
+    Events {
+        key: RequestKey<CK>,
+        events: Vec<Event>,
+        next_cursor: Option<Event>,
+    },
+    #[serde(rename_all = "camelCase")]
+    ExpressionEvents {
+        expression: KeyExpression<CK>,
+        events: Vec<Event>,
+        next_cursor: Option<Event>,
+    },
+    EventsBatch(Vec<KeyEvents<CK>>),
+    EventCount {
+        key: RequestKey<CK>,
+        count: u64,
+    },
     Subscribed,
     Unsubscribed,
     SizeOnDisk(u64),
-    //    Error,
+    Reindexing(Span),
+    /// Events of a best block that has been retracted by a reorg
+    Retracted {
+        key: Key<CK>,
+        events: Vec<Event>,
+    },
+    /// Events of a block that has been finalized
+    Finalized {
+        key: Key<CK>,
+        events: Vec<Event>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+/// JSON response message with the id of the request it belongs to
+///
+/// Subscription notifications have the id of the request that subscribed.
+#[derive(Serialize, Debug, Clone)]
+pub struct Response<CK: IndexKey> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    #[serde(flatten)]
+    pub msg: ResponseMessage<CK>,
+}
+
+/// Error codes for JSON error responses
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    ParseError,
+    UnknownKeyType,
+    BackendUnavailable,
+    LimitExceeded,
+    Forbidden,
 }
 
 /// Subscription message sent from a WebSocket connection thread to the indexer thread
 #[derive(Debug)]
 pub enum SubscriptionMessage<CK: IndexKey> {
     SubscribeStatus {
-        sub_response_tx: UnboundedSender<ResponseMessage<CK>>,
+        id: Option<u32>,
+        sub_response_tx: UnboundedSender<Response<CK>>,
     },
     UnsubscribeStatus {
-        sub_response_tx: UnboundedSender<ResponseMessage<CK>>,
+        sub_response_tx: UnboundedSender<Response<CK>>,
     },
     SubscribeEvents {
-        key: Key<CK>,
-        sub_response_tx: UnboundedSender<ResponseMessage<CK>>,
+        key: RequestKey<CK>,
+        /// The key with variant names resolved
+        resolved: KeyExpression<CK>,
+        since: Option<Event>,
+        id: Option<u32>,
+        sub_response_tx: UnboundedSender<Response<CK>>,
     },
     UnsubscribeEvents {
-        key: Key<CK>,
-        sub_response_tx: UnboundedSender<ResponseMessage<CK>>,
+        resolved: KeyExpression<CK>,
+        sub_response_tx: UnboundedSender<Response<CK>>,
     },
 }
+
+/// Admin message sent from a WebSocket connection thread to the indexer thread
+#[derive(Debug)]
+pub enum AdminMessage {
+    Reindex { span: Span },
+}
@@ -0,0 +1,209 @@
+//! Portable snapshots of an index database.
+//!
+//! A snapshot starts with a header of `SNAPSHOT_MAGIC` and the format version as a big-endian `u16`. Each tree follows as a tree record (`0x01`, name length as `u16`, name) and then an entry record (`0x02`, key length as `u32`, key, value length as `u32`, value) for each key. The root tree, which contains the genesis hash and schema version, comes first and has an empty name. An end record (`0x00`) is followed by the 32 byte Blake2b hash of everything before it.
+
+use crate::{
+    schema,
+    shared::*,
+    storage::{BatchWriter, Tree},
+};
+use blake2::{digest::consts::U32, Blake2b, Digest};
+use std::io::{Read, Write};
+use tracing::info;
+
+/// Identifies a snapshot file
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"ACIDXSNP";
+
+/// Version of the snapshot format
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+const RECORD_END: u8 = 0;
+const RECORD_TREE: u8 = 1;
+const RECORD_ENTRY: u8 = 2;
+
+/// Largest key or value accepted when importing, so a corrupt length cannot exhaust memory before the hash is checked
+const MAX_ENTRY_LEN: u32 = 1 << 20;
+
+type Hasher = Blake2b<U32>;
+
+/// Writer that hashes everything written to it
+struct HashWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that hashes everything read from it
+struct HashReader<R: Read> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], IndexError> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads the length of a key or value and then the key or value.
+fn read_entry_vec(reader: &mut impl Read) -> Result<Vec<u8>, IndexError> {
+    let len = u32::from_be_bytes(read_bytes(reader)?);
+    if len > MAX_ENTRY_LEN {
+        return Err(IndexError::InvalidSnapshot("entry too large"));
+    }
+    read_vec(reader, len.try_into().unwrap())
+}
+
+fn read_vec(reader: &mut impl Read, len: usize) -> Result<Vec<u8>, IndexError> {
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn export_tree(writer: &mut impl Write, name: &[u8], tree: &Tree) -> Result<u64, IndexError> {
+    writer.write_all(&[RECORD_TREE])?;
+    writer.write_all(&u16::try_from(name.len()).unwrap().to_be_bytes())?;
+    writer.write_all(name)?;
+    let mut count = 0;
+    for item in tree.iter() {
+        let (key, value) = item?;
+        writer.write_all(&[RECORD_ENTRY])?;
+        writer.write_all(&u32::try_from(key.len()).unwrap().to_be_bytes())?;
+        writer.write_all(&key)?;
+        writer.write_all(&u32::try_from(value.len()).unwrap().to_be_bytes())?;
+        writer.write_all(&value)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Exports all the trees of the database to a snapshot, returning the number of entries exported. The trees are read while they are live rather than from a point-in-time view, so the indexer must be stopped while exporting or the snapshot may be inconsistent.
+pub fn export_snapshot<CT>(trees: &Trees<CT>, writer: impl Write) -> Result<u64, IndexError> {
+    let mut writer = HashWriter {
+        inner: writer,
+        hasher: Hasher::new(),
+    };
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    writer.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+    let mut count = export_tree(&mut writer, b"", &trees.root)?;
+    let mut names = trees.root.tree_names()?;
+    names.sort();
+    for name in names {
+        let tree = trees.root.open_tree(&name)?;
+        count += export_tree(&mut writer, &name, &tree)?;
+    }
+    writer.write_all(&[RECORD_END])?;
+    let hash = writer.hasher.finalize();
+    writer.inner.write_all(&hash)?;
+    writer.inner.flush()?;
+    info!("Exported {} entries.", count);
+    Ok(count)
+}
+
+fn import_records<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    reader: impl Read,
+) -> Result<u64, IndexError> {
+    let mut reader = HashReader {
+        inner: reader,
+        hasher: Hasher::new(),
+    };
+    if read_bytes::<8>(&mut reader)? != *SNAPSHOT_MAGIC {
+        return Err(IndexError::InvalidSnapshot("not a snapshot"));
+    }
+    if u16::from_be_bytes(read_bytes(&mut reader)?) != SNAPSHOT_VERSION {
+        return Err(IndexError::InvalidSnapshot("unsupported snapshot version"));
+    }
+    let mut writer: Option<BatchWriter> = None;
+    let mut count = 0;
+    loop {
+        let [record] = read_bytes(&mut reader)?;
+        // The entries of a tree end with the next record that is not an entry.
+        if record != RECORD_ENTRY {
+            if let Some(writer) = writer.take() {
+                writer.finish()?;
+            }
+        }
+        match record {
+            RECORD_END => break,
+            RECORD_TREE => {
+                let len = u16::from_be_bytes(read_bytes(&mut reader)?);
+                let name = read_vec(&mut reader, len.into())?;
+                let tree: Tree = match name.is_empty() {
+                    true => (*trees.root).clone(),
+                    false => trees.root.open_tree(&name)?,
+                };
+                writer = Some(BatchWriter::new(tree));
+            }
+            RECORD_ENTRY => {
+                let Some(writer) = &mut writer else {
+                    return Err(IndexError::InvalidSnapshot("entry outside tree"));
+                };
+                let key = read_entry_vec(&mut reader)?;
+                let value = read_entry_vec(&mut reader)?;
+                writer.insert(key, value)?;
+                count += 1;
+            }
+            _ => return Err(IndexError::InvalidSnapshot("unknown record")),
+        }
+    }
+    // Verify the snapshot.
+    let hash = reader.hasher.finalize();
+    if read_bytes::<32>(&mut reader.inner)?[..] != hash[..] {
+        return Err(IndexError::InvalidSnapshot("hash mismatch"));
+    }
+    match trees.root.get("genesis_hash")? {
+        Some(genesis_hash) if genesis_hash == R::get_genesis_hash().as_ref() => {}
+        _ => return Err(IndexError::InvalidSnapshot("wrong genesis hash")),
+    }
+    schema::check_schema(&trees.root)?;
+    trees.root.flush()?;
+    Ok(count)
+}
+
+/// Imports a snapshot into an empty database, returning the number of entries imported. The snapshot is verified and the database is emptied again if it is invalid.
+pub fn import_snapshot<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    reader: impl Read,
+) -> Result<u64, IndexError> {
+    if !trees.root.is_empty()? {
+        return Err(IndexError::InvalidSnapshot("database is not empty"));
+    }
+    for name in trees.root.tree_names()? {
+        if !trees.root.open_tree(name)?.is_empty()? {
+            return Err(IndexError::InvalidSnapshot("database is not empty"));
+        }
+    }
+    match import_records::<R>(trees, reader) {
+        Ok(count) => {
+            info!("Imported {} entries.", count);
+            Ok(count)
+        }
+        Err(err) => {
+            for name in trees.root.tree_names()? {
+                trees.root.open_tree(name)?.clear()?;
+            }
+            trees.root.clear()?;
+            Err(err)
+        }
+    }
+}
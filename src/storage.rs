@@ -0,0 +1,774 @@
+//! Storage backends for the index database.
+//!
+//! The indexer and the query layer only use the [`Db`] and [`Tree`] handles, so any ordered
+//! key/value store can be plugged in by implementing [`DbBackend`] and [`TreeBackend`].
+
+use sled::{transaction::TransactionError, Transactional};
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    ops::{Bound, Deref, RangeBounds},
+    sync::{Arc, Mutex, RwLock},
+};
+
+#[cfg(feature = "redb")]
+use redb::{ReadableTableMetadata, TableHandle};
+
+/// Errors a storage backend can return
+#[derive(thiserror::Error, Debug)]
+pub enum StorageError {
+    #[error("sled error")]
+    Sled(#[from] sled::Error),
+    #[cfg(feature = "redb")]
+    #[error("redb error")]
+    Redb(#[from] redb::Error),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+}
+
+/// Iterator over the key / value pairs of a tree in key order
+pub type Iter = Box<dyn DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>), StorageError>>>;
+
+/// Database engines that can be selected when starting the indexer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DbEngine {
+    #[default]
+    Sled,
+    #[cfg(feature = "redb")]
+    Redb,
+}
+
+/// A database made up of named ordered key / value trees
+pub trait DbBackend: Send + Sync {
+    /// Opens the tree with the given name, creating it if it does not exist.
+    fn open_tree(&self, name: &[u8]) -> Result<Tree, StorageError>;
+    /// Opens the tree that database-wide values such as the genesis hash are stored in.
+    fn root_tree(&self) -> Result<Tree, StorageError>;
+    /// Names of all the trees in the database, not including the root tree.
+    fn tree_names(&self) -> Result<Vec<Vec<u8>>, StorageError>;
+    fn size_on_disk(&self) -> Result<u64, StorageError>;
+    /// Applies the batches for all the trees atomically. The trees were opened from this database.
+    fn apply_batch(&self, batches: Vec<(Tree, Batch)>) -> Result<(), StorageError>;
+    fn flush(&self) -> Result<(), StorageError>;
+}
+
+/// An ordered key / value tree
+pub trait TreeBackend: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError>;
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError>;
+    fn remove(&self, key: &[u8]) -> Result<(), StorageError>;
+    /// Iterates the key / value pairs within the bounds, which are never reversed.
+    fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Iter;
+    /// Applies all the writes in the batch atomically.
+    fn apply_batch(&self, batch: Batch) -> Result<(), StorageError>;
+    fn len(&self) -> Result<usize, StorageError>;
+    fn is_empty(&self) -> Result<bool, StorageError> {
+        Ok(self.len()? == 0)
+    }
+    fn clear(&self) -> Result<(), StorageError>;
+    fn flush(&self) -> Result<(), StorageError>;
+    /// Allows the database backend to downcast its own trees when applying a [`DbBatch`].
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// A set of writes to be applied to a tree atomically
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Batch {
+    pub fn insert(&mut self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.ops
+            .push((key.as_ref().to_vec(), Some(value.as_ref().to_vec())));
+    }
+
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) {
+        self.ops.push((key.as_ref().to_vec(), None));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the writes in order. A value of `None` is a removal.
+    pub fn into_ops(self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.ops
+    }
+}
+
+/// A set of writes to be applied to multiple trees atomically
+#[derive(Clone, Debug, Default)]
+pub struct DbBatch {
+    batches: Vec<(Tree, Batch)>,
+}
+
+impl DbBatch {
+    fn tree_batch(&mut self, tree: &Tree) -> &mut Batch {
+        let i = match self
+            .batches
+            .iter()
+            .position(|(batch_tree, _)| Arc::ptr_eq(&batch_tree.0, &tree.0))
+        {
+            Some(i) => i,
+            None => {
+                self.batches.push((tree.clone(), Batch::default()));
+                self.batches.len() - 1
+            }
+        };
+        &mut self.batches[i].1
+    }
+
+    pub fn insert(&mut self, tree: &Tree, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) {
+        self.tree_batch(tree).insert(key, value);
+    }
+
+    pub fn remove(&mut self, tree: &Tree, key: impl AsRef<[u8]>) {
+        self.tree_batch(tree).remove(key);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.iter().all(|(_, batch)| batch.is_empty())
+    }
+
+    /// Returns the batch for each tree.
+    pub fn into_batches(self) -> Vec<(Tree, Batch)> {
+        self.batches
+    }
+}
+
+/// Number of writes applied to a tree in each batch by [`BatchWriter`]
+pub const WRITE_BATCH_SIZE: usize = 10_000;
+
+/// Applies bulk writes to a tree in batches of [`WRITE_BATCH_SIZE`], so they are not all held in memory
+pub struct BatchWriter {
+    tree: Tree,
+    batch: Batch,
+    len: usize,
+}
+
+impl BatchWriter {
+    pub fn new(tree: Tree) -> Self {
+        BatchWriter {
+            tree,
+            batch: Batch::default(),
+            len: 0,
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<(), StorageError> {
+        self.batch.insert(key, value);
+        self.written()
+    }
+
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Result<(), StorageError> {
+        self.batch.remove(key);
+        self.written()
+    }
+
+    fn written(&mut self) -> Result<(), StorageError> {
+        self.len += 1;
+        if self.len == WRITE_BATCH_SIZE {
+            self.tree.apply_batch(std::mem::take(&mut self.batch))?;
+            self.len = 0;
+        }
+        Ok(())
+    }
+
+    /// Applies the remaining writes.
+    pub fn finish(self) -> Result<(), StorageError> {
+        self.tree.apply_batch(self.batch)
+    }
+}
+
+/// Handle to a tree in the database
+#[derive(Clone)]
+pub struct Tree(Arc<dyn TreeBackend>);
+
+impl Tree {
+    pub fn new(backend: impl TreeBackend + 'static) -> Self {
+        Tree(Arc::new(backend))
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>, StorageError> {
+        self.0.get(key.as_ref())
+    }
+
+    pub fn insert(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<(), StorageError> {
+        self.0.insert(key.as_ref(), value.as_ref())
+    }
+
+    pub fn remove(&self, key: impl AsRef<[u8]>) -> Result<(), StorageError> {
+        self.0.remove(key.as_ref())
+    }
+
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Iter {
+        let start = range.start_bound().map(|key| key.as_ref().to_vec());
+        let end = range.end_bound().map(|key| key.as_ref().to_vec());
+        if range_is_empty(&start, &end) {
+            return Box::new(std::iter::empty());
+        }
+        self.0.range(start, end)
+    }
+
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Iter {
+        let prefix = prefix.as_ref().to_vec();
+        // The first key after all the keys with the prefix.
+        let mut end = prefix.clone();
+        while let Some(last) = end.pop() {
+            if last < u8::MAX {
+                end.push(last + 1);
+                return self.range((Bound::Included(prefix), Bound::Excluded(end)));
+            }
+        }
+        self.range((Bound::Included(prefix), Bound::Unbounded))
+    }
+
+    pub fn iter(&self) -> Iter {
+        self.0.range(Bound::Unbounded, Bound::Unbounded)
+    }
+
+    pub fn apply_batch(&self, batch: Batch) -> Result<(), StorageError> {
+        self.0.apply_batch(batch)
+    }
+
+    pub fn len(&self) -> Result<usize, StorageError> {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> Result<bool, StorageError> {
+        self.0.is_empty()
+    }
+
+    pub fn clear(&self) -> Result<(), StorageError> {
+        self.0.clear()
+    }
+
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.0.flush()
+    }
+
+    pub fn backend(&self) -> &dyn TreeBackend {
+        self.0.as_ref()
+    }
+}
+
+impl fmt::Debug for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.len() {
+            Ok(len) => write!(f, "Tree({} keys)", len),
+            Err(err) => write!(f, "Tree({})", err),
+        }
+    }
+}
+
+fn range_is_empty(start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start >= end,
+        _ => false,
+    }
+}
+
+/// Handle to the database. Dereferences to the root tree.
+#[derive(Clone)]
+pub struct Db {
+    backend: Arc<dyn DbBackend>,
+    root: Tree,
+}
+
+impl Db {
+    pub fn new(backend: impl DbBackend + 'static) -> Result<Self, StorageError> {
+        let root = backend.root_tree()?;
+        Ok(Db {
+            backend: Arc::new(backend),
+            root,
+        })
+    }
+
+    /// Opens a sled database.
+    pub fn sled(config: sled::Config) -> Result<Self, StorageError> {
+        Db::new(SledDb(config.open()?))
+    }
+
+    /// Creates an empty database that is only held in memory.
+    pub fn memory() -> Self {
+        Db::new(MemoryDb::default()).unwrap()
+    }
+
+    /// Opens a redb database file, creating it if it does not exist.
+    #[cfg(feature = "redb")]
+    pub fn redb(
+        path: impl AsRef<std::path::Path>,
+        cache_capacity: u64,
+    ) -> Result<Self, StorageError> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let db = redb::Builder::new()
+            .set_cache_size(cache_capacity.try_into().unwrap_or(usize::MAX))
+            .create(path)
+            .map_err(redb::Error::from)?;
+        Db::new(RedbDb {
+            db: Arc::new(db),
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn open_tree(&self, name: impl AsRef<[u8]>) -> Result<Tree, StorageError> {
+        self.backend.open_tree(name.as_ref())
+    }
+
+    /// Names of all the trees in the database, not including the root tree.
+    pub fn tree_names(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+        self.backend.tree_names()
+    }
+
+    pub fn size_on_disk(&self) -> Result<u64, StorageError> {
+        self.backend.size_on_disk()
+    }
+
+    /// Applies a batch of writes to multiple trees atomically.
+    pub fn apply_batch(&self, batch: DbBatch) -> Result<(), StorageError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.backend.apply_batch(batch.batches)
+    }
+
+    /// Flushes all the trees in the database.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        self.backend.flush()
+    }
+}
+
+impl Deref for Db {
+    type Target = Tree;
+
+    fn deref(&self) -> &Tree {
+        &self.root
+    }
+}
+
+struct SledDb(sled::Db);
+
+impl DbBackend for SledDb {
+    fn open_tree(&self, name: &[u8]) -> Result<Tree, StorageError> {
+        Ok(Tree::new(SledTree(self.0.open_tree(name)?)))
+    }
+
+    fn root_tree(&self) -> Result<Tree, StorageError> {
+        // Existing databases store the genesis hash in the default tree.
+        Ok(Tree::new(SledTree((*self.0).clone())))
+    }
+
+    fn tree_names(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+        Ok(self
+            .0
+            .tree_names()
+            .into_iter()
+            .filter(|name| name != b"__sled__default")
+            .map(|name| name.to_vec())
+            .collect())
+    }
+
+    fn size_on_disk(&self) -> Result<u64, StorageError> {
+        Ok(self.0.size_on_disk()?)
+    }
+
+    fn apply_batch(&self, batches: Vec<(Tree, Batch)>) -> Result<(), StorageError> {
+        let (trees, batches): (Vec<_>, Vec<_>) = batches
+            .into_iter()
+            .map(|(tree, batch)| {
+                (
+                    downcast_tree::<SledTree>(&tree).0.clone(),
+                    sled_batch(batch),
+                )
+            })
+            .unzip();
+        trees[..]
+            .transaction(|trees| {
+                for (tree, batch) in trees.iter().zip(&batches) {
+                    tree.apply_batch(batch)?;
+                }
+                Ok(())
+            })
+            .map_err(|err: TransactionError<()>| match err {
+                TransactionError::Storage(err) => err,
+                TransactionError::Abort(()) => unreachable!(),
+            })?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+struct SledTree(sled::Tree);
+
+fn sled_batch(batch: Batch) -> sled::Batch {
+    let mut sled_batch = sled::Batch::default();
+    for (key, value) in batch.into_ops() {
+        match value {
+            Some(value) => sled_batch.insert(key, value),
+            None => sled_batch.remove(key),
+        }
+    }
+    sled_batch
+}
+
+/// Gets the backend of a tree that must have been opened from the same database.
+fn downcast_tree<T: TreeBackend + 'static>(tree: &Tree) -> &T {
+    tree.backend()
+        .as_any()
+        .downcast_ref()
+        .expect("tree is from a different database")
+}
+
+impl TreeBackend for SledTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.0.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.0.insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), StorageError> {
+        self.0.remove(key)?;
+        Ok(())
+    }
+
+    fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Iter {
+        Box::new(self.0.range((start, end)).map(|item| {
+            let (key, value) = item?;
+            Ok((key.to_vec(), value.to_vec()))
+        }))
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), StorageError> {
+        self.0.apply_batch(sled_batch(batch))?;
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize, StorageError> {
+        Ok(self.0.len())
+    }
+
+    fn clear(&self) -> Result<(), StorageError> {
+        self.0.clear()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.0.flush()?;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Default)]
+struct MemoryDb {
+    trees: Mutex<HashMap<Vec<u8>, Tree>>,
+}
+
+impl DbBackend for MemoryDb {
+    fn open_tree(&self, name: &[u8]) -> Result<Tree, StorageError> {
+        let mut trees = self.trees.lock().unwrap();
+        let tree = trees
+            .entry(name.to_vec())
+            .or_insert_with(|| Tree::new(MemoryTree::default()));
+        Ok(tree.clone())
+    }
+
+    fn root_tree(&self) -> Result<Tree, StorageError> {
+        self.open_tree(b"__root")
+    }
+
+    fn tree_names(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+        Ok(self
+            .trees
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|name| *name != b"__root")
+            .cloned()
+            .collect())
+    }
+
+    fn size_on_disk(&self) -> Result<u64, StorageError> {
+        Ok(0)
+    }
+
+    fn apply_batch(&self, batches: Vec<(Tree, Batch)>) -> Result<(), StorageError> {
+        // Hold the locks for all the trees while writing, always locking in the same order.
+        let mut batches: Vec<_> = batches
+            .iter()
+            .map(|(tree, batch)| (downcast_tree::<MemoryTree>(tree), batch))
+            .collect();
+        batches.sort_by_key(|(tree, _)| *tree as *const MemoryTree);
+        let mut maps: Vec<_> = batches
+            .iter()
+            .map(|(tree, _)| tree.0.write().unwrap())
+            .collect();
+        for (map, (_, batch)) in maps.iter_mut().zip(batches) {
+            apply_to_map(map, batch.clone());
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct MemoryTree(RwLock<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+fn apply_to_map(map: &mut BTreeMap<Vec<u8>, Vec<u8>>, batch: Batch) {
+    for (key, value) in batch.into_ops() {
+        match value {
+            Some(value) => map.insert(key, value),
+            None => map.remove(&key),
+        };
+    }
+}
+
+impl TreeBackend for MemoryTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.0.read().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.0.write().unwrap().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), StorageError> {
+        self.0.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Iter {
+        // Copy the range so the lock is not held while iterating.
+        let items: Vec<_> = self
+            .0
+            .read()
+            .unwrap()
+            .range((start, end))
+            .map(|(key, value)| Ok((key.clone(), value.clone())))
+            .collect();
+        Box::new(items.into_iter())
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), StorageError> {
+        apply_to_map(&mut self.0.write().unwrap(), batch);
+        Ok(())
+    }
+
+    fn len(&self) -> Result<usize, StorageError> {
+        Ok(self.0.read().unwrap().len())
+    }
+
+    fn clear(&self) -> Result<(), StorageError> {
+        self.0.write().unwrap().clear();
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(feature = "redb")]
+struct RedbDb {
+    db: Arc<redb::Database>,
+    path: std::path::PathBuf,
+}
+
+#[cfg(feature = "redb")]
+impl DbBackend for RedbDb {
+    fn open_tree(&self, name: &[u8]) -> Result<Tree, StorageError> {
+        let tree = RedbTree {
+            db: self.db.clone(),
+            name: String::from_utf8_lossy(name).into_owned(),
+        };
+        // Create the table so that read transactions can always open it.
+        tree.write(|_| Ok(()))?;
+        Ok(Tree::new(tree))
+    }
+
+    fn root_tree(&self) -> Result<Tree, StorageError> {
+        self.open_tree(b"__root")
+    }
+
+    fn tree_names(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+        let tx = self.db.begin_read().map_err(redb::Error::from)?;
+        let names = tx
+            .list_tables()
+            .map_err(redb::Error::from)?
+            .map(|table| table.name().as_bytes().to_vec())
+            .filter(|name| name != b"__root")
+            .collect();
+        Ok(names)
+    }
+
+    fn size_on_disk(&self) -> Result<u64, StorageError> {
+        Ok(std::fs::metadata(&self.path)?.len())
+    }
+
+    fn apply_batch(&self, batches: Vec<(Tree, Batch)>) -> Result<(), StorageError> {
+        let mut tx = self.db.begin_write().map_err(redb::Error::from)?;
+        tx.set_durability(redb::Durability::Eventual);
+        for (tree, batch) in batches {
+            let tree = downcast_tree::<RedbTree>(&tree);
+            let mut table = tx.open_table(tree.table()).map_err(redb::Error::from)?;
+            apply_to_table(&mut table, batch)?;
+        }
+        tx.commit().map_err(redb::Error::from)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        // An immediately durable commit also persists all the eventually durable commits before it.
+        let mut tx = self.db.begin_write().map_err(redb::Error::from)?;
+        tx.set_durability(redb::Durability::Immediate);
+        tx.commit().map_err(redb::Error::from)?;
+        Ok(())
+    }
+}
+
+/// A redb table. Each `insert` and `remove` is its own write transaction, and redb only allows one write transaction at a time, so bulk writes should be made with `apply_batch` or a [`DbBatch`].
+#[cfg(feature = "redb")]
+struct RedbTree {
+    db: Arc<redb::Database>,
+    name: String,
+}
+
+#[cfg(feature = "redb")]
+fn apply_to_table(
+    table: &mut redb::Table<&'static [u8], &'static [u8]>,
+    batch: Batch,
+) -> Result<(), redb::Error> {
+    for (key, value) in batch.into_ops() {
+        match value {
+            Some(value) => table.insert(key.as_slice(), value.as_slice())?,
+            None => table.remove(key.as_slice())?,
+        };
+    }
+    Ok(())
+}
+
+#[cfg(feature = "redb")]
+impl RedbTree {
+    fn table(&self) -> redb::TableDefinition<'_, &'static [u8], &'static [u8]> {
+        redb::TableDefinition::new(&self.name)
+    }
+
+    fn read(&self) -> Result<redb::ReadOnlyTable<&'static [u8], &'static [u8]>, redb::Error> {
+        Ok(self.db.begin_read()?.open_table(self.table())?)
+    }
+
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut redb::Table<&'static [u8], &'static [u8]>) -> Result<T, redb::Error>,
+    ) -> Result<T, StorageError> {
+        let mut tx = self.db.begin_write().map_err(redb::Error::from)?;
+        tx.set_durability(redb::Durability::Eventual);
+        let result = {
+            let mut table = tx.open_table(self.table()).map_err(redb::Error::from)?;
+            f(&mut table)?
+        };
+        tx.commit().map_err(redb::Error::from)?;
+        Ok(result)
+    }
+}
+
+#[cfg(feature = "redb")]
+impl TreeBackend for RedbTree {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let table = self.read()?;
+        let value = table.get(key).map_err(redb::Error::from)?;
+        Ok(value.map(|value| value.value().to_vec()))
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        self.write(|table| {
+            table.insert(key, value)?;
+            Ok(())
+        })
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), StorageError> {
+        self.write(|table| {
+            table.remove(key)?;
+            Ok(())
+        })
+    }
+
+    fn range(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Iter {
+        let range = self.read().and_then(|table| {
+            let start = start.as_ref().map(Vec::as_slice);
+            let end = end.as_ref().map(Vec::as_slice);
+            Ok(table.range::<&[u8]>((start, end))?)
+        });
+        match range {
+            Ok(range) => Box::new(range.map(|item| {
+                let (key, value) = item.map_err(redb::Error::from)?;
+                Ok((key.value().to_vec(), value.value().to_vec()))
+            })),
+            Err(err) => Box::new(std::iter::once(Err(err.into()))),
+        }
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), StorageError> {
+        self.write(|table| apply_to_table(table, batch))
+    }
+
+    fn len(&self) -> Result<usize, StorageError> {
+        let len = self.read()?.len().map_err(redb::Error::from)?;
+        Ok(len.try_into().unwrap())
+    }
+
+    fn clear(&self) -> Result<(), StorageError> {
+        self.write(|table| {
+            table.retain(|_, _| false)?;
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        // Trees share a single file, so flush the whole database.
+        let mut tx = self.db.begin_write().map_err(redb::Error::from)?;
+        tx.set_durability(redb::Durability::Immediate);
+        tx.commit().map_err(redb::Error::from)?;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
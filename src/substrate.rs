@@ -1,60 +1,139 @@
 use ahash::AHashMap;
-use futures::future;
+use futures::future::{self, OptionFuture};
 use num_format::{Locale, ToFormattedString};
-use sled::Tree;
-use std::{collections::HashMap, future::Future, sync::Mutex};
-use subxt::{backend::legacy::LegacyRpcMethods, blocks::Block, metadata::Metadata, OnlineClient};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use subxt::{backend::legacy::LegacyRpcMethods, blocks::Block, OnlineClient};
 use tokio::{
-    sync::{mpsc, watch, RwLock},
+    sync::{mpsc, watch, Mutex as AsyncMutex, RwLock},
+    task::{self, JoinHandle},
     time::{self, Duration, Instant, MissedTickBehavior},
 };
 use tracing::{debug, error, info};
 use zerocopy::{AsBytes, FromBytes};
 
-use crate::{shared::*, websockets::process_msg_status};
+use crate::{
+    endpoints::EndpointPool,
+    prune::{
+        carve_span_records, carve_spans, delete_keys, prune_spans, prune_trees,
+        retention_min_block, spawn_prune_trees, PRUNE_INTERVAL,
+    },
+    queue::{BatchStats, QueueDepthController},
+    shared::*,
+    storage::{Db, DbBatch, StorageError, Tree},
+    websockets::{get_resolved_key_events, process_error, process_msg_status},
+};
+
+/// Keys of a block that are committed to the database together with the span change for the block
+pub struct BlockBatch<CK: IndexKey> {
+    pub batch: DbBatch,
+    pub events: Vec<(Key<CK>, Event)>,
+}
+
+impl<CK: IndexKey> BlockBatch<CK> {
+    fn new() -> Self {
+        BlockBatch {
+            batch: DbBatch::default(),
+            events: Vec::new(),
+        }
+    }
+}
+
+/// Hash of a block of the chain being indexed
+pub type BlockHash<R> = <<R as RuntimeIndexer>::RuntimeConfig as subxt::Config>::Hash;
+
+/// Best block that has been indexed but not finalized
+pub struct BestBlock<R: RuntimeIndexer + ?Sized> {
+    pub hash: BlockHash<R>,
+    pub events: Vec<(Key<R::ChainKey>, Event)>,
+}
 
 #[allow(clippy::type_complexity)]
 pub struct Indexer<R: RuntimeIndexer + ?Sized> {
     trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
-    api: Option<OnlineClient<R::RuntimeConfig>>,
-    rpc: Option<LegacyRpcMethods<R::RuntimeConfig>>,
+    api: Mutex<Option<OnlineClient<R::RuntimeConfig>>>,
+    rpc: Mutex<Option<LegacyRpcMethods<R::RuntimeConfig>>>,
+    endpoints: EndpointPool<R>,
+    batch_stats: Mutex<BatchStats>,
     index_variant: bool,
-    metadata_map_lock: RwLock<AHashMap<u32, Metadata>>,
-    status_sub: Mutex<Vec<mpsc::UnboundedSender<ResponseMessage<R::ChainKey>>>>,
-    events_sub_map:
-        Mutex<HashMap<Key<R::ChainKey>, Vec<mpsc::UnboundedSender<ResponseMessage<R::ChainKey>>>>>,
+    metadata_map_lock: Arc<RwLock<MetadataMap>>,
+    status_sub: Mutex<Vec<(Option<u32>, mpsc::UnboundedSender<Response<R::ChainKey>>)>>,
+    events_sub_map: Mutex<
+        HashMap<Key<R::ChainKey>, Vec<(Option<u32>, mpsc::UnboundedSender<Response<R::ChainKey>>)>>,
+    >,
+    block_batches: Mutex<AHashMap<u32, BlockBatch<R::ChainKey>>>,
+    best_blocks: Mutex<BTreeMap<u32, BestBlock<R>>>,
+    head_lock: AsyncMutex<()>,
 }
 
 impl<R: RuntimeIndexer> Indexer<R> {
-    fn new(
+    pub(crate) fn new(
         trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
         api: OnlineClient<R::RuntimeConfig>,
         rpc: LegacyRpcMethods<R::RuntimeConfig>,
+        endpoints: EndpointPool<R>,
         index_variant: bool,
+        metadata_map_lock: Arc<RwLock<MetadataMap>>,
     ) -> Self {
         Indexer {
             trees,
-            api: Some(api),
-            rpc: Some(rpc),
+            api: Some(api).into(),
+            rpc: Some(rpc).into(),
+            endpoints,
+            batch_stats: BatchStats::default().into(),
             index_variant,
-            metadata_map_lock: RwLock::new(AHashMap::new()),
+            metadata_map_lock,
             status_sub: Vec::new().into(),
             events_sub_map: HashMap::new().into(),
+            block_batches: AHashMap::new().into(),
+            best_blocks: BTreeMap::new().into(),
+            head_lock: AsyncMutex::new(()),
         }
     }
 
     pub fn new_test(trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>) -> Self {
         Indexer {
             trees,
-            api: None,
-            rpc: None,
+            api: None.into(),
+            rpc: None.into(),
+            endpoints: EndpointPool::default(),
+            batch_stats: BatchStats::default().into(),
             index_variant: true,
-            metadata_map_lock: RwLock::new(AHashMap::new()),
+            metadata_map_lock: Arc::new(RwLock::new(AHashMap::new())),
             status_sub: Vec::new().into(),
             events_sub_map: HashMap::new().into(),
+            block_batches: AHashMap::new().into(),
+            best_blocks: BTreeMap::new().into(),
+            head_lock: AsyncMutex::new(()),
         }
     }
 
+    /// Replaces the connection to the node after reconnecting.
+    pub fn set_client(
+        &self,
+        api: OnlineClient<R::RuntimeConfig>,
+        rpc: LegacyRpcMethods<R::RuntimeConfig>,
+    ) {
+        *self.api.lock().unwrap() = Some(api);
+        *self.rpc.lock().unwrap() = Some(rpc);
+    }
+
+    fn api(&self) -> OnlineClient<R::RuntimeConfig> {
+        self.api.lock().unwrap().clone().unwrap()
+    }
+
+    fn rpc(&self) -> LegacyRpcMethods<R::RuntimeConfig> {
+        self.rpc.lock().unwrap().clone().unwrap()
+    }
+
+    /// Returns the stats of the batch block requests since they were last taken.
+    fn take_batch_stats(&self) -> BatchStats {
+        std::mem::take(&mut *self.batch_stats.lock().unwrap())
+    }
+
     async fn index_head(
         &self,
         next: impl Future<
@@ -62,20 +141,211 @@ impl<R: RuntimeIndexer> Indexer<R> {
                 Result<Block<R::RuntimeConfig, OnlineClient<R::RuntimeConfig>>, subxt::Error>,
             >,
         >,
-    ) -> Result<(u32, u32, u32), IndexError> {
-        let block = next.await.unwrap()?;
-        self.index_block(block.number().into().try_into().unwrap())
-            .await
+        retry: RetryConfig,
+    ) -> Result<(u32, u32, u32, Option<BlockBatch<R::ChainKey>>), IndexError> {
+        let block = next.await.ok_or(IndexError::SubscriptionClosed)??;
+        let block_number = block.number().into().try_into().unwrap();
+        let block_hash = block.hash();
+        let _head_lock = self.head_lock.lock().await;
+        // The block has already been indexed as a best block.
+        if self.is_best_block(block_number, &block_hash) {
+            return Ok((block_number, 0, 0, None));
+        }
+        // The head block cannot be skipped, so retry it before giving up on the connection.
+        let mut delay = retry.backoff;
+        for attempt in 1..=retry.attempts {
+            match self
+                .index_block_hash(block_number, Some(block_hash), &self.api(), &self.rpc())
+                .await
+            {
+                Ok((block_number, event_count, key_count, block_batch)) => {
+                    return Ok((block_number, event_count, key_count, Some(block_batch)))
+                }
+                Err(error) => {
+                    error!(
+                        "✨ Block #{} failed, retry {} of {} in {:?}: {}",
+                        block_number.to_formatted_string(&Locale::en),
+                        attempt,
+                        retry.attempts,
+                        delay,
+                        error
+                    );
+                    time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+        let (block_number, event_count, key_count, block_batch) = self
+            .index_block_hash(block_number, Some(block_hash), &self.api(), &self.rpc())
+            .await?;
+        Ok((block_number, event_count, key_count, Some(block_batch)))
+    }
+
+    /// Indexes a new best block and the blocks of its branch that have not been indexed as best blocks, oldest first.
+    #[allow(clippy::type_complexity)]
+    async fn index_best_head(
+        &self,
+        next: impl Future<
+            Output = Option<
+                Result<Block<R::RuntimeConfig, OnlineClient<R::RuntimeConfig>>, subxt::Error>,
+            >,
+        >,
+        finalized: u32,
+    ) -> Result<Vec<(u32, BlockHash<R>, u32, u32, BlockBatch<R::ChainKey>)>, IndexError> {
+        let block = next.await.ok_or(IndexError::SubscriptionClosed)??;
+        let mut block_number: u32 = block.number().into().try_into().unwrap();
+        let mut block_hash = block.hash();
+        let rpc = self.rpc();
+        let _head_lock = self.head_lock.lock().await;
+        // Walk back until the branch reaches a block that has already been indexed.
+        let mut branch = Vec::new();
+        while block_number > finalized && !self.is_best_block(block_number, &block_hash) {
+            branch.push((block_number, block_hash));
+            block_number -= 1;
+            if block_number > finalized {
+                block_hash = match rpc.chain_get_block_hash(Some(block_number.into())).await? {
+                    Some(block_hash) => block_hash,
+                    None => return Err(IndexError::BlockNotFound(block_number)),
+                };
+            }
+        }
+        let api = self.api();
+        let mut blocks = Vec::with_capacity(branch.len());
+        for (block_number, block_hash) in branch.into_iter().rev() {
+            let (block_number, event_count, key_count, block_batch) = self
+                .index_block_hash(block_number, Some(block_hash), &api, &rpc)
+                .await?;
+            blocks.push((
+                block_number,
+                block_hash,
+                event_count,
+                key_count,
+                block_batch,
+            ));
+        }
+        Ok(blocks)
+    }
+
+    /// Indexes a block from an endpoint not in `exclude`. Returns the index of the endpoint with the result, or `None` if every endpoint is excluded or disabled.
+    #[allow(clippy::type_complexity)]
+    async fn index_block(
+        &self,
+        block_number: u32,
+        exclude: &[usize],
+    ) -> Option<(
+        usize,
+        Result<(u32, u32, u32, BlockBatch<R::ChainKey>), IndexError>,
+    )> {
+        // Batch blocks are spread across the endpoints.
+        let endpoint = self.endpoints.acquire(exclude).await?;
+        let start = Instant::now();
+        let result = self
+            .index_block_hash(block_number, None, endpoint.api(), endpoint.rpc())
+            .await;
+        self.batch_stats
+            .lock()
+            .unwrap()
+            .record(start.elapsed(), result.is_ok());
+        match result {
+            // The endpoint answered, it just does not have the block.
+            Err(ref error) if error.is_block_unavailable() => endpoint.record_success(),
+            Err(IndexError::Subxt(_)) => endpoint.record_failure(),
+            _ => endpoint.record_success(),
+        }
+        Some((endpoint.index(), result))
+    }
+
+    /// Indexes a block, retrying with exponential backoff if it fails. A block that an endpoint does not have is tried on the other endpoints instead. Returns the block number with the result.
+    #[allow(clippy::type_complexity)]
+    async fn index_block_retry(
+        &self,
+        block_number: u32,
+        retry: RetryConfig,
+    ) -> (
+        u32,
+        Result<(u32, u32, u32, BlockBatch<R::ChainKey>), IndexError>,
+    ) {
+        let mut delay = retry.backoff;
+        let mut attempt = 0;
+        // Endpoints that do not have the block or its state
+        let mut unavailable = Vec::new();
+        let mut unavailable_error = IndexError::BlockNotFound(block_number);
+        loop {
+            let (index, result) = match self.index_block(block_number, &unavailable).await {
+                Some(result) => result,
+                None => return (block_number, Err(unavailable_error)),
+            };
+            match result {
+                Ok(result) => return (block_number, Ok(result)),
+                Err(error) if error.is_block_unavailable() => {
+                    debug!(
+                        "📚 Block #{} is not available from endpoint {}, trying another: {}",
+                        block_number.to_formatted_string(&Locale::en),
+                        index,
+                        error
+                    );
+                    unavailable.push(index);
+                    unavailable_error = error;
+                }
+                Err(error) if attempt < retry.attempts => {
+                    attempt += 1;
+                    debug!(
+                        "📚 Block #{} failed, retry {} of {} in {:?}: {}",
+                        block_number.to_formatted_string(&Locale::en),
+                        attempt,
+                        retry.attempts,
+                        delay,
+                        error
+                    );
+                    time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(error) => return (block_number, Err(error)),
+            }
+        }
     }
 
-    async fn index_block(&self, block_number: u32) -> Result<(u32, u32, u32), IndexError> {
+    /// Indexes the block with a hash, or the block on the best chain of the node if no hash is given.
+    async fn index_block_hash(
+        &self,
+        block_number: u32,
+        block_hash: Option<BlockHash<R>>,
+        api: &OnlineClient<R::RuntimeConfig>,
+        rpc: &LegacyRpcMethods<R::RuntimeConfig>,
+    ) -> Result<(u32, u32, u32, BlockBatch<R::ChainKey>), IndexError> {
+        // Collect the keys of the block so they can be committed together with the span change.
+        self.block_batches
+            .lock()
+            .unwrap()
+            .insert(block_number, BlockBatch::new());
+        let result = self
+            .index_block_events(block_number, block_hash, api, rpc)
+            .await;
+        let block_batch = self
+            .block_batches
+            .lock()
+            .unwrap()
+            .remove(&block_number)
+            .unwrap();
+        let (event_count, key_count) = result?;
+        Ok((block_number, event_count, key_count, block_batch))
+    }
+
+    async fn index_block_events(
+        &self,
+        block_number: u32,
+        block_hash: Option<BlockHash<R>>,
+        api: &OnlineClient<R::RuntimeConfig>,
+        rpc: &LegacyRpcMethods<R::RuntimeConfig>,
+    ) -> Result<(u32, u32), IndexError> {
         let mut key_count = 0;
-        let api = self.api.as_ref().unwrap();
-        let rpc = self.rpc.as_ref().unwrap();
 
-        let block_hash = match rpc.chain_get_block_hash(Some(block_number.into())).await? {
+        let block_hash = match block_hash {
             Some(block_hash) => block_hash,
-            None => return Err(IndexError::BlockNotFound(block_number)),
+            None => match rpc.chain_get_block_hash(Some(block_number.into())).await? {
+                Some(block_hash) => block_hash,
+                None => return Err(IndexError::BlockNotFound(block_number)),
+            },
         };
         // Get the runtime version of the block.
         let runtime_version = rpc.state_get_runtime_version(Some(block_hash)).await?;
@@ -135,14 +405,18 @@ impl<R: RuntimeIndexer> Indexer<R> {
             }
         }
 
-        Ok((block_number, events.len(), key_count))
+        Ok((events.len(), key_count))
     }
 
     pub fn notify_status_subscribers(&self) {
-        let msg = process_msg_status::<R>(&self.trees.span);
+        let msg = process_msg_status::<R>(&self.trees);
         let txs = self.status_sub.lock().unwrap();
-        for tx in txs.iter() {
-            if tx.send(msg.clone()).is_ok() {}
+        for (id, tx) in txs.iter() {
+            let response = Response {
+                id: *id,
+                msg: msg.clone(),
+            };
+            if tx.send(response).is_ok() {}
         }
     }
 
@@ -150,31 +424,267 @@ impl<R: RuntimeIndexer> Indexer<R> {
         let events_sub_map = self.events_sub_map.lock().unwrap();
         if let Some(txs) = events_sub_map.get(&search_key) {
             let msg = ResponseMessage::Events {
-                key: search_key,
+                key: search_key.into(),
                 events: vec![event],
+                next_cursor: None,
             };
-            for tx in txs.iter() {
-                if tx.send(msg.clone()).is_ok() {}
+            for (id, tx) in txs.iter() {
+                let response = Response {
+                    id: *id,
+                    msg: msg.clone(),
+                };
+                if tx.send(response).is_ok() {}
+            }
+        }
+    }
+
+    /// Sends the events of a block to the subscribers of each key, grouped by key.
+    fn notify_block_subscribers(
+        &self,
+        events: Vec<(Key<R::ChainKey>, Event)>,
+        msg: impl Fn(Key<R::ChainKey>, Vec<Event>) -> ResponseMessage<R::ChainKey>,
+    ) {
+        let mut key_events: Vec<(Key<R::ChainKey>, Vec<Event>)> = Vec::new();
+        for (key, event) in events {
+            match key_events.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, events)) => events.push(event),
+                None => key_events.push((key, vec![event])),
+            }
+        }
+        let events_sub_map = self.events_sub_map.lock().unwrap();
+        for (key, events) in key_events {
+            if let Some(txs) = events_sub_map.get(&key) {
+                let msg = msg(key, events);
+                for (id, tx) in txs.iter() {
+                    let response = Response {
+                        id: *id,
+                        msg: msg.clone(),
+                    };
+                    if tx.send(response).is_ok() {}
+                }
             }
         }
     }
 
+    /// Returns all the events for a resolved key after an event in pages, oldest first. Returns an error if there are more than `MAX_REPLAY_EVENTS`, so the replay cannot hold up indexing.
+    pub fn replay_events(
+        &self,
+        resolved: &KeyExpression<R::ChainKey>,
+        since: Event,
+    ) -> Result<Vec<Vec<Event>>, IndexError> {
+        let mut query = EventQuery {
+            cursor: Some(since),
+            limit: Some(MAX_EVENTS_LIMIT),
+            order: Order::Ascending,
+            ..Default::default()
+        };
+        let mut pages = Vec::new();
+        let mut count = 0;
+        loop {
+            let (events, next_cursor) = get_resolved_key_events::<R>(&self.trees, resolved, &query);
+            count += events.len();
+            if count > MAX_REPLAY_EVENTS {
+                return Err(IndexError::LimitExceeded {
+                    limit: count,
+                    max: MAX_REPLAY_EVENTS,
+                });
+            }
+            if !events.is_empty() {
+                pages.push(events);
+            }
+            match next_cursor {
+                Some(next_cursor) => query.cursor = Some(next_cursor),
+                None => break,
+            }
+        }
+        Ok(pages)
+    }
+
+    /// Indexes an event by a key. If the block is being indexed the key is added to the batch for the block, otherwise it is written immediately.
     pub fn index_event(
         &self,
         key: Key<R::ChainKey>,
         block_number: u32,
         event_index: u16,
-    ) -> Result<(), sled::Error> {
-        key.write_db_key(&self.trees, block_number, event_index)?;
-        self.notify_subscribers(
-            key,
-            Event {
-                block_number,
-                event_index,
+    ) -> Result<(), StorageError> {
+        let event = Event {
+            block_number,
+            event_index,
+        };
+        let mut block_batches = self.block_batches.lock().unwrap();
+        match block_batches.get_mut(&block_number) {
+            Some(block_batch) => {
+                key.write_db_key(
+                    &self.trees,
+                    &mut block_batch.batch,
+                    block_number,
+                    event_index,
+                );
+                block_batch.events.push((key, event));
+            }
+            None => {
+                drop(block_batches);
+                let mut batch = DbBatch::default();
+                key.write_db_key(&self.trees, &mut batch, block_number, event_index);
+                self.trees.root.apply_batch(batch)?;
+                self.notify_subscribers(key, event);
+            }
+        }
+        Ok(())
+    }
+
+    /// Indexes a block again without writing its keys, returning the number of them that are missing from the database.
+    pub async fn check_block(&self, block_number: u32) -> Result<u32, IndexError> {
+        let (_, _, _, block_batch) = self
+            .index_block_hash(block_number, None, &self.api(), &self.rpc())
+            .await?;
+        let mut missing = 0;
+        for (tree, batch) in block_batch.batch.into_batches() {
+            for (key, value) in batch.into_ops() {
+                if value.is_some() && tree.get(key)?.is_none() {
+                    missing += 1;
+                }
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Atomically writes the keys of a block and any span changes added to its batch, then notifies subscribers.
+    pub fn commit_block(&self, block_batch: BlockBatch<R::ChainKey>) -> Result<(), StorageError> {
+        self.trees.root.apply_batch(block_batch.batch)?;
+        for (key, event) in block_batch.events {
+            self.notify_subscribers(key, event);
+        }
+        Ok(())
+    }
+
+    /// Checks if a block has been indexed as a best block.
+    pub fn is_best_block(&self, block_number: u32, block_hash: &BlockHash<R>) -> bool {
+        self.best_blocks
+            .lock()
+            .unwrap()
+            .get(&block_number)
+            .is_some_and(|best_block| best_block.hash == *block_hash)
+    }
+
+    /// Writes the keys of a best block, keeping track of them so they can be deleted if the block is retracted.
+    pub fn commit_best_block(
+        &self,
+        block_number: u32,
+        block_hash: BlockHash<R>,
+        mut block_batch: BlockBatch<R::ChainKey>,
+    ) -> Result<(), StorageError> {
+        let mut best_blocks = self.best_blocks.lock().unwrap();
+        best_blocks.insert(
+            block_number,
+            BestBlock {
+                hash: block_hash,
+                events: block_batch.events.clone(),
             },
         );
+        write_best_span(&self.trees.root, &mut block_batch.batch, &best_blocks);
+        drop(best_blocks);
+        self.commit_block(block_batch)
+    }
+
+    /// Deletes the keys of the best blocks from `block_number` onwards, returning the blocks.
+    pub fn delete_best_blocks(
+        &self,
+        block_number: u32,
+    ) -> Result<BTreeMap<u32, BestBlock<R>>, StorageError> {
+        let mut best_blocks = self.best_blocks.lock().unwrap();
+        let deleted = best_blocks.split_off(&block_number);
+        if deleted.is_empty() {
+            return Ok(deleted);
+        }
+        let mut batch = DbBatch::default();
+        for best_block in deleted.values() {
+            for (key, event) in &best_block.events {
+                key.write_db_key(
+                    &self.trees,
+                    &mut batch,
+                    event.block_number,
+                    event.event_index,
+                );
+            }
+        }
+        let mut removals = DbBatch::default();
+        for (tree, batch) in batch.into_batches() {
+            for (key, _) in batch.into_ops() {
+                removals.remove(&tree, key);
+            }
+        }
+        write_best_span(&self.trees.root, &mut removals, &best_blocks);
+        self.trees.root.apply_batch(removals)?;
+        Ok(deleted)
+    }
+
+    /// Deletes the keys of the best blocks from `block_number` onwards and notifies subscribers that their events have been retracted.
+    pub fn retract_best_blocks(&self, block_number: u32) -> Result<(), StorageError> {
+        for (block_number, best_block) in self.delete_best_blocks(block_number)? {
+            info!(
+                "🔀 Best block #{} retracted",
+                block_number.to_formatted_string(&Locale::en)
+            );
+            self.notify_block_subscribers(best_block.events, |key, events| {
+                ResponseMessage::Retracted { key, events }
+            });
+        }
         Ok(())
     }
+
+    /// Stops tracking the best blocks up to a finalized block, adding the change to its batch. Returns the events of the blocks.
+    pub fn finalize_best_blocks(
+        &self,
+        block_number: u32,
+        batch: &mut DbBatch,
+    ) -> Vec<(Key<R::ChainKey>, Event)> {
+        let mut best_blocks = self.best_blocks.lock().unwrap();
+        let unfinalized = best_blocks.split_off(&(block_number + 1));
+        let finalized = std::mem::replace(&mut *best_blocks, unfinalized);
+        if finalized.is_empty() {
+            return Vec::new();
+        }
+        write_best_span(&self.trees.root, batch, &best_blocks);
+        finalized
+            .into_values()
+            .flat_map(|best_block| best_block.events)
+            .collect()
+    }
+
+    /// Notifies subscribers that the events of a block are final.
+    pub fn notify_finalized(&self, events: Vec<(Key<R::ChainKey>, Event)>) {
+        self.notify_block_subscribers(events, |key, events| ResponseMessage::Finalized {
+            key,
+            events,
+        });
+    }
+}
+
+/// Reads the span of best blocks that have been indexed but not finalized.
+pub fn read_best_span(db: &Db) -> Result<Option<Span>, IndexError> {
+    Ok(match db.get("best_span")? {
+        Some(value) => Some(serde_json::from_slice(&value)?),
+        None => None,
+    })
+}
+
+/// Adds a record of the span of best blocks to the batch, so their keys can be deleted if the indexer stops before they are finalized.
+pub fn write_best_span<R: RuntimeIndexer + ?Sized>(
+    db: &Db,
+    batch: &mut DbBatch,
+    best_blocks: &BTreeMap<u32, BestBlock<R>>,
+) {
+    match (best_blocks.first_key_value(), best_blocks.last_key_value()) {
+        (Some((start, _)), Some((end, _))) => {
+            let span = Span {
+                start: *start,
+                end: *end,
+            };
+            batch.insert(db, "best_span", serde_json::to_vec(&span).unwrap());
+        }
+        _ => batch.remove(db, "best_span"),
+    }
 }
 
 pub fn load_spans<R: RuntimeIndexer>(
@@ -182,10 +692,10 @@ pub fn load_spans<R: RuntimeIndexer>(
     index_variant: bool,
 ) -> Result<Vec<Span>, IndexError> {
     let mut spans = vec![];
-    'span: for (key, value) in span_db.into_iter().flatten() {
+    'span: for (key, value) in span_db.iter().flatten() {
         let span_value = SpanDbValue::read_from(&value).unwrap();
         let start: u32 = span_value.start.into();
-        let mut end: u32 = u32::from_be_bytes(key.as_ref().try_into().unwrap());
+        let mut end: u32 = u32::from_be_bytes(key.as_slice().try_into().unwrap());
         // Check if variants are supposed to be indexed and they were not in this span.
         if index_variant && (span_value.index_variant != 1) {
             // Delete the span.
@@ -233,11 +743,27 @@ pub fn load_spans<R: RuntimeIndexer>(
     Ok(spans)
 }
 
+/// Adds a span record for the span to the batch.
+pub fn write_span<R: RuntimeIndexer>(
+    span_db: &Tree,
+    batch: &mut DbBatch,
+    span: &Span,
+    index_variant: bool,
+) {
+    let value = SpanDbValue {
+        start: span.start.into(),
+        version: (R::get_versions().len() - 1).try_into().unwrap(),
+        index_variant: index_variant.into(),
+    };
+    batch.insert(span_db, span.end.to_be_bytes(), value.as_bytes());
+}
+
 pub fn check_span(
     span_db: &Tree,
+    batch: &mut DbBatch,
     spans: &mut Vec<Span>,
     current_span: &mut Span,
-) -> Result<(), IndexError> {
+) {
     while let Some(span) = spans.last() {
         // Have we indexed all the blocks after the span?
         if current_span.start > span.start && current_span.start - 1 <= span.end {
@@ -250,22 +776,95 @@ pub fn check_span(
             );
             current_span.start = span.start;
             // Remove the span.
-            span_db.remove(span.end.to_be_bytes())?;
+            batch.remove(span_db, span.end.to_be_bytes());
             spans.pop();
         } else {
             break;
         }
     }
-    Ok(())
 }
 
-pub fn check_next_batch_block(spans: &[Span], next_batch_block: &mut u32) {
+/// Records an orphaned block as a span, merging it with any adjacent orphaned blocks. `orphans` maps the end block of each orphan span to its start block.
+pub fn add_orphan<R: RuntimeIndexer>(
+    span_db: &Tree,
+    batch: &mut DbBatch,
+    orphans: &mut BTreeMap<u32, u32>,
+    block_number: u32,
+    index_variant: bool,
+) {
+    let mut span = Span {
+        start: block_number,
+        end: block_number,
+    };
+    // Merge with the orphan span below.
+    if let Some(start) = block_number
+        .checked_sub(1)
+        .and_then(|below| orphans.remove(&below))
+    {
+        batch.remove(span_db, (block_number - 1).to_be_bytes());
+        span.start = start;
+    }
+    // Merge with the orphan span above. Its record is overwritten below.
+    let above = orphans
+        .range(block_number + 1..)
+        .next()
+        .filter(|(_, start)| **start == block_number + 1)
+        .map(|(end, _)| *end);
+    if let Some(end) = above {
+        orphans.remove(&end);
+        span.end = end;
+    }
+    orphans.insert(span.end, span.start);
+    write_span::<R>(span_db, batch, &span, index_variant);
+}
+
+/// Checks if a block has already been indexed in the current span or as an orphan.
+pub fn is_block_indexed(
+    current_span: &Span,
+    orphans: &BTreeMap<u32, u32>,
+    block_number: u32,
+) -> bool {
+    (current_span.start..=current_span.end).contains(&block_number)
+        || orphans
+            .range(block_number..)
+            .next()
+            .is_some_and(|(_, start)| *start <= block_number)
+}
+
+/// Skips the spans that `next_batch_block` has reached. `None` means the backfill has passed genesis.
+pub fn check_next_batch_block(spans: &[Span], next_batch_block: &mut Option<u32>) {
     // Figure out the next block to index, skipping the next span if we have reached it.
     let mut i = spans.len();
     while i != 0 {
         i -= 1;
-        if *next_batch_block >= spans[i].start && *next_batch_block <= spans[i].end {
-            *next_batch_block = spans[i].start - 1;
+        if next_batch_block.is_some_and(|block_number| {
+            block_number >= spans[i].start && block_number <= spans[i].end
+        }) {
+            *next_batch_block = spans[i].start.checked_sub(1);
+        }
+    }
+}
+
+/// Takes the next block to be batch indexed and adds it to `in_flight`, or returns `None` once the backfill has reached `min_block` or genesis. Blocks that are already in flight are skipped, as are the blocks in `deleting` while their keys are being deleted.
+pub fn take_next_batch_block(
+    spans: &[Span],
+    next_batch_block: &mut Option<u32>,
+    min_block: u32,
+    in_flight: &mut BTreeSet<u32>,
+    deleting: Option<&Span>,
+) -> Option<u32> {
+    loop {
+        check_next_batch_block(spans, next_batch_block);
+        let block_number = next_batch_block.filter(|block_number| *block_number >= min_block)?;
+        if let Some(range) =
+            deleting.filter(|range| range.start <= block_number && block_number <= range.end)
+        {
+            *next_batch_block = range.start.checked_sub(1);
+            continue;
+        }
+        *next_batch_block = block_number.checked_sub(1);
+        if in_flight.insert(block_number) {
+            return Some(block_number);
         }
     }
 }
@@ -275,50 +874,89 @@ pub fn process_sub_msg<R: RuntimeIndexer>(
     msg: SubscriptionMessage<R::ChainKey>,
 ) {
     match msg {
-        SubscriptionMessage::SubscribeStatus { sub_response_tx } => {
+        SubscriptionMessage::SubscribeStatus {
+            id,
+            sub_response_tx,
+        } => {
             let mut txs = indexer.status_sub.lock().unwrap();
-            txs.push(sub_response_tx);
+            txs.push((id, sub_response_tx));
         }
         SubscriptionMessage::UnsubscribeStatus { sub_response_tx } => {
             let mut txs = indexer.status_sub.lock().unwrap();
-            txs.retain(|value| !sub_response_tx.same_channel(value));
+            txs.retain(|(_, value)| !sub_response_tx.same_channel(value));
         }
         SubscriptionMessage::SubscribeEvents {
             key,
+            resolved,
+            since,
+            id,
             sub_response_tx,
         } => {
-            let mut events_sub_map = indexer.events_sub_map.lock().unwrap();
-            match events_sub_map.get_mut(&key) {
-                Some(txs) => {
-                    txs.push(sub_response_tx);
-                }
-                None => {
-                    let txs = vec![sub_response_tx];
-                    events_sub_map.insert(key, txs);
+            // Events are indexed on this thread, so no events can be indexed between replaying and subscribing.
+            let pages = match since.map(|since| indexer.replay_events(&resolved, since)) {
+                Some(Ok(pages)) => pages,
+                Some(Err(error)) => {
+                    let msg = process_error::<R>(error);
+                    sub_response_tx.send(Response { id, msg }).ok();
+                    return;
                 }
+                None => Vec::new(),
             };
+            // The subscription is only acknowledged once it is known to be made.
+            let msg = ResponseMessage::Subscribed;
+            sub_response_tx.send(Response { id, msg }).ok();
+            for events in pages {
+                let msg = ResponseMessage::Events {
+                    key: key.clone(),
+                    events,
+                    next_cursor: None,
+                };
+                if sub_response_tx.send(Response { id, msg }).is_err() {
+                    break;
+                }
+            }
+            // A variant name is subscribed to under each of the variants it has had.
+            let mut events_sub_map = indexer.events_sub_map.lock().unwrap();
+            for key in resolved.keys() {
+                events_sub_map
+                    .entry(key.clone())
+                    .or_default()
+                    .push((id, sub_response_tx.clone()));
+            }
         }
         SubscriptionMessage::UnsubscribeEvents {
-            key,
+            resolved,
             sub_response_tx,
         } => {
             let mut events_sub_map = indexer.events_sub_map.lock().unwrap();
-            if let Some(txs) = events_sub_map.get_mut(&key) {
-                txs.retain(|value| !sub_response_tx.same_channel(value));
-            };
+            for key in resolved.keys() {
+                if let Some(txs) = events_sub_map.get_mut(key) {
+                    txs.retain(|(_, value)| !sub_response_tx.same_channel(value));
+                };
+            }
         }
     };
 }
 
+/// Indexes the chain until the exit signal is received. Returns an error if the connection to the node is lost, after which it can be called again with the same indexer to carry on.
 pub async fn substrate_index<R: RuntimeIndexer>(
-    trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
-    api: OnlineClient<R::RuntimeConfig>,
-    rpc: LegacyRpcMethods<R::RuntimeConfig>,
-    queue_depth: u32,
-    index_variant: bool,
+    indexer: &Indexer<R>,
+    config: &IndexerConfig,
     mut exit_rx: watch::Receiver<bool>,
-    mut sub_rx: mpsc::UnboundedReceiver<SubscriptionMessage<R::ChainKey>>,
+    sub_rx: &mut mpsc::UnboundedReceiver<SubscriptionMessage<R::ChainKey>>,
+    admin_rx: &mut mpsc::UnboundedReceiver<AdminMessage>,
 ) -> Result<(), IndexError> {
+    let trees = indexer.trees.clone();
+    let index_variant = indexer.index_variant;
+    let IndexerConfig {
+        queue_depth,
+        index_best,
+        index_range,
+        retention,
+        retry,
+        ..
+    } = *config;
+    let api = indexer.api();
     info!(
         "📇 Event variant indexing: {}",
         match index_variant {
@@ -326,10 +964,16 @@ pub async fn substrate_index<R: RuntimeIndexer>(
             true => "enabled",
         },
     );
+    info!(
+        "🌱 Best block indexing: {}",
+        match index_best {
+            false => "disabled",
+            true => "enabled",
+        },
+    );
     // Subscribe to all finalized blocks:
     let mut blocks_sub = api.blocks().subscribe_finalized().await?;
-    // Determine the correct block to start batch indexing.
-    let mut next_batch_block: u32 = blocks_sub
+    let head: u32 = blocks_sub
         .next()
         .await
         .ok_or(IndexError::BlockNotFound(0))??
@@ -337,15 +981,70 @@ pub async fn substrate_index<R: RuntimeIndexer>(
         .into()
         .try_into()
         .unwrap();
-    info!(
-        "📚 Indexing backwards from #{}",
-        next_batch_block.to_formatted_string(&Locale::en)
-    );
-    // Load already indexed spans from the db.
+    // Subscribe to best blocks.
+    let mut best_sub = match index_best {
+        true => Some(api.blocks().subscribe_best().await?),
+        false => None,
+    };
+    // Determine the correct block to start batch indexing.
+    let first_batch_block = match index_range.end {
+        Some(end) => end.min(head),
+        None => head,
+    };
+    let mut next_batch_block = Some(first_batch_block);
+    // Only follow the head if the end of the range has not been reached.
+    let mut is_following_head = match index_range.end {
+        Some(end) => end > head,
+        None => true,
+    };
+    if index_range.backfill {
+        info!(
+            "📚 Indexing backwards from #{} to #{}",
+            first_batch_block.to_formatted_string(&Locale::en),
+            index_range.start.to_formatted_string(&Locale::en)
+        );
+    }
+    if let Some(end) = index_range.end {
+        info!(
+            "✨ Indexing up to #{}",
+            end.to_formatted_string(&Locale::en)
+        );
+    }
+    // Delete the keys of best blocks that were not finalized when the indexer stopped.
+    if let Some(best_span) = read_best_span(&trees.root)? {
+        info!(
+            "🌱 Deleting unfinalized best blocks from #{} to #{}",
+            best_span.start.to_formatted_string(&Locale::en),
+            best_span.end.to_formatted_string(&Locale::en)
+        );
+        delete_keys(&trees.root, &best_span)?;
+        trees.root.remove("best_span")?;
+    }
+    // Delete keys outside the retention window before loading the spans.
+    let mut min_block = index_range.start;
+    if let Some(retention) = retention {
+        let retention_block = retention_min_block(head, retention);
+        info!(
+            "🗑️  Retaining the last {} blocks from #{}",
+            retention.to_formatted_string(&Locale::en),
+            retention_block.to_formatted_string(&Locale::en)
+        );
+        prune_trees(&trees, retention_block)?;
+        min_block = min_block.max(retention_block);
+    }
+    let mut last_prune_block = head;
+    IndexRange {
+        start: min_block,
+        ..index_range
+    }
+    .write(&trees.root)?;
+    // Load already indexed spans from the db. Spans after the end of the range are left alone.
     let mut spans = load_spans::<R>(&trees.span, index_variant)?;
-    // If the first head block to be indexed will be touching the last span (the indexer was restarted), set the current span to the last span. Otherwise there will be no batch block indexed to connect the current span to the last span.
+    spans.retain(|span| span.start <= first_batch_block);
+    let last_span_end = spans.last().map(|span| span.end);
+    // If the first block to be indexed is touching or inside the last span (the indexer was restarted), set the current span to the last span. Otherwise there will be no batch block indexed to connect the current span to the last span.
     let mut current_span = if let Some(span) = spans.last()
-        && span.end == next_batch_block
+        && span.end >= first_batch_block
     {
         let span = span.clone();
         let skipped = span.end - span.start + 1;
@@ -358,33 +1057,70 @@ pub async fn substrate_index<R: RuntimeIndexer>(
         // Remove the span.
         trees.span.remove(span.end.to_be_bytes())?;
         spans.pop();
-        next_batch_block = span.start - 1;
+        next_batch_block = span.start.checked_sub(1);
         span
     } else {
+        // Nothing has been indexed yet.
         Span {
-            start: next_batch_block + 1,
-            end: next_batch_block + 1,
+            start: first_batch_block + 1,
+            end: first_batch_block,
         }
     };
+    // Without backfill only blocks after the last span are indexed, so blocks finalized while the indexer was not running are still indexed. The first time, indexing starts at the current head.
+    if !index_range.backfill {
+        min_block = min_block.max(match last_span_end {
+            Some(end) => current_span.start.min(end + 1),
+            None => first_batch_block,
+        });
+    }
 
-    let indexer = Indexer::<R>::new(trees.clone(), api, rpc, index_variant);
-
-    let mut head_future = Box::pin(indexer.index_head(blocks_sub.next()));
+    let mut head_future = Box::pin(indexer.index_head(blocks_sub.next(), retry));
+    let mut best_future = best_sub
+        .as_mut()
+        .map(|best_sub| Box::pin(indexer.index_best_head(best_sub.next(), current_span.end)));
 
     info!("📚 Queue depth: {}", queue_depth);
+    let mut queue_depth_controller = None;
+    let mut queue_depth = match queue_depth {
+        QueueDepth::Fixed(depth) => depth,
+        QueueDepth::Adaptive { min, max } => queue_depth_controller
+            .insert(QueueDepthController::new(min, max))
+            .depth(),
+    };
+    // Discard the stats of blocks from before reconnecting.
+    indexer.take_batch_stats();
     let mut futures = Vec::with_capacity(queue_depth.try_into().unwrap());
+    // Blocks that are queued, so a re-index doesn't queue them twice.
+    let mut in_flight = BTreeSet::new();
 
-    for _ in 0..queue_depth {
-        check_next_batch_block(&spans, &mut next_batch_block);
-        futures.push(Box::pin(indexer.index_block(next_batch_block)));
+    while futures.len() < queue_depth.try_into().unwrap() {
+        let Some(block_number) = take_next_batch_block(
+            &spans,
+            &mut next_batch_block,
+            min_block,
+            &mut in_flight,
+            None,
+        ) else {
+            break;
+        };
+        futures.push(Box::pin(indexer.index_block_retry(block_number, retry)));
         debug!(
             "⬆️  Block #{} queued.",
-            next_batch_block.to_formatted_string(&Locale::en)
+            block_number.to_formatted_string(&Locale::en)
         );
-        next_batch_block -= 1;
     }
 
-    let mut orphans: AHashMap<u32, ()> = AHashMap::new();
+    // Blocks that could not be indexed are recorded until they have been indexed.
+    let failed_count = trees.failed.len()?;
+    if failed_count != 0 {
+        info!(
+            "📚 {} blocks failed to be indexed previously",
+            failed_count.to_formatted_string(&Locale::en)
+        );
+    }
+
+    // Orphaned blocks are recorded as spans so they are not indexed again after a restart.
+    let mut orphans: BTreeMap<u32, u32> = BTreeMap::new();
 
     let mut stats_block_count = 0;
     let mut stats_event_count = 0;
@@ -395,73 +1131,199 @@ pub async fn substrate_index<R: RuntimeIndexer>(
     let mut interval = time::interval_at(Instant::now() + interval_duration, interval_duration);
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-    let mut is_batching = true;
+    let mut is_batching = !futures.is_empty();
+    let mut prune_task: Option<JoinHandle<()>> = None;
+    // Range of blocks being re-indexed while its keys are deleted.
+    let mut reindex_task: Option<(Span, JoinHandle<Result<u64, IndexError>>)> = None;
 
-    loop {
+    let result = loop {
         tokio::select! {
             biased;
 
-            _ = exit_rx.changed() => {
-                if current_span.start != current_span.end {
-                    let value = SpanDbValue {
-                        start: current_span.start.into(),
-                        version: (R::get_versions().len() - 1).try_into().unwrap(),
-                        index_variant: index_variant.into(),
+            _ = exit_rx.changed() => break Ok(()),
+            Some(msg) = sub_rx.recv() => process_sub_msg(indexer, msg),
+            // Re-indexes are made one at a time.
+            Some(msg) = admin_rx.recv(), if reindex_task.is_none() => match msg {
+                AdminMessage::Reindex { span } => {
+                    // Only blocks in the range being indexed can be re-indexed.
+                    let range = Span {
+                        start: span.start.max(min_block),
+                        end: span.end.min(current_span.end),
                     };
-                    trees.span.insert(current_span.end.to_be_bytes(), value.as_bytes())?;
-                    info!(
-                        "📚 Recording current indexed span from #{} to #{}",
-                        current_span.start.to_formatted_string(&Locale::en),
-                        current_span.end.to_formatted_string(&Locale::en)
-                    );
+                    if range.start > range.end {
+                        info!("📚 No blocks to re-index from #{} to #{}", span.start.to_formatted_string(&Locale::en), span.end.to_formatted_string(&Locale::en));
+                        continue;
+                    }
+                    info!("📚 Re-indexing blocks from #{} to #{}", range.start.to_formatted_string(&Locale::en), range.end.to_formatted_string(&Locale::en));
+                    carve_span_records(&trees.span, &range)?;
+                    carve_spans(&mut spans, &mut orphans, &range);
+                    // Keep the part of the current span before the range as a span.
+                    if current_span.start <= range.end {
+                        if current_span.start < range.start {
+                            spans.push(Span { start: current_span.start, end: range.start - 1 });
+                        }
+                        current_span.start = range.end + 1;
+                    }
+                    // Every tree is swept on a blocking thread so the head keeps being followed. No blocks in the range are queued or committed until it has finished.
+                    let db = trees.root.clone();
+                    let deleting = range.clone();
+                    let task = task::spawn_blocking(move || delete_keys(&db, &deleting));
+                    reindex_task = Some((range, task));
+                    indexer.notify_status_subscribers();
+                },
+            },
+            Some(result) = OptionFuture::from(reindex_task.as_mut().map(|(_, task)| task)), if reindex_task.is_some() => {
+                let (range, _) = reindex_task.take().unwrap();
+                match result {
+                    Ok(Ok(count)) => debug!("📚 Deleted {} keys.", count.to_formatted_string(&Locale::en)),
+                    // Indexing the blocks again writes the same keys.
+                    Ok(Err(err)) => error!("📚 Deleting keys failed: {}", err),
+                    Err(err) => error!("📚 Deleting keys failed: {}", err),
                 }
-                return Ok(());
+                // Queue the range again if the batch has already passed it. Blocks still in flight are skipped.
+                next_batch_block = next_batch_block.max(Some(range.end));
+                while futures.len() < queue_depth.try_into().unwrap() {
+                    let Some(block_number) = take_next_batch_block(&spans, &mut next_batch_block, min_block, &mut in_flight, None) else {
+                        break;
+                    };
+                    futures.push(Box::pin(indexer.index_block_retry(block_number, retry)));
+                    debug!("⬆️  Block #{} queued.", block_number.to_formatted_string(&Locale::en));
+                }
+                is_batching = !futures.is_empty();
             }
-            Some(msg) = sub_rx.recv() => process_sub_msg(&indexer, msg),
-            result = &mut head_future => {
+            result = &mut head_future, if is_following_head => {
                 match result {
-                    Ok((block_number, event_count, key_count)) => {
-                        trees.span.remove(current_span.end.to_be_bytes())?;
-                        current_span.end = block_number;
-                        let value = SpanDbValue {
-                            start: current_span.start.into(),
-                            version: (R::get_versions().len() - 1).try_into().unwrap(),
-                            index_variant: index_variant.into(),
+                    Ok((block_number, event_count, key_count, block_batch)) => {
+                        let is_best_block = block_batch.is_none();
+                        let mut block_batch = match block_batch {
+                            Some(block_batch) => {
+                                // Best blocks from this block onwards are on a different branch.
+                                indexer.retract_best_blocks(block_number)?;
+                                block_batch
+                            }
+                            None => BlockBatch::new(),
                         };
-                        trees.span.insert(current_span.end.to_be_bytes(), value.as_bytes())?;
-                        info!(
-                            "✨ #{}: {} events, {} keys",
-                            block_number.to_formatted_string(&Locale::en),
-                            event_count.to_formatted_string(&Locale::en),
-                            key_count.to_formatted_string(&Locale::en),
-                        );
+                        let mut finalized = indexer.finalize_best_blocks(block_number, &mut block_batch.batch);
+                        if index_best {
+                            finalized.extend(block_batch.events.iter().cloned());
+                        }
+                        block_batch.batch.remove(&trees.span, current_span.end.to_be_bytes());
+                        current_span.end = block_number;
+                        write_span::<R>(&trees.span, &mut block_batch.batch, &current_span, index_variant);
+                        indexer.commit_block(block_batch)?;
+                        if index_best {
+                            indexer.notify_finalized(finalized);
+                        }
+                        // Move the retention window forward.
+                        // The previous sweep must have finished before the next one.
+                        let is_pruning = prune_task.as_ref().is_some_and(|task| !task.is_finished());
+                        if let Some(retention) = retention.filter(|_| !is_pruning && block_number >= last_prune_block + PRUNE_INTERVAL) {
+                            let retention_block = retention_min_block(block_number, retention);
+                            prune_spans(&mut spans, &mut current_span, &mut orphans, retention_block);
+                            // Batch blocks before the new window are discarded, so no keys are written where the sweep is deleting.
+                            min_block = min_block.max(retention_block);
+                            prune_task = spawn_prune_trees(&trees, retention_block)?;
+                            IndexRange { start: min_block, ..index_range }.write(&trees.root)?;
+                            last_prune_block = block_number;
+                        }
+                        if index_range.end == Some(block_number) {
+                            info!("✨ Reached the end of the range at #{}", block_number.to_formatted_string(&Locale::en));
+                            is_following_head = false;
+                        }
+                        if is_best_block {
+                            info!("✨ #{} finalized", block_number.to_formatted_string(&Locale::en));
+                        }
+                        else {
+                            info!(
+                                "✨ #{}: {} events, {} keys",
+                                block_number.to_formatted_string(&Locale::en),
+                                event_count.to_formatted_string(&Locale::en),
+                                key_count.to_formatted_string(&Locale::en),
+                            );
+                        }
                         indexer.notify_status_subscribers();
                         drop(head_future);
-                        head_future = Box::pin(indexer.index_head(blocks_sub.next()));
+                        head_future = Box::pin(indexer.index_head(blocks_sub.next(), retry));
                     },
                     Err(error) => {
-                        match error {
+                        match &error {
                             IndexError::BlockNotFound(block_number) => {
                                 error!("✨ Block not found #{}", block_number.to_formatted_string(&Locale::en));
                             },
+                            IndexError::SubscriptionClosed => {
+                                error!("✨ Finalized block subscription closed");
+                            },
                             err => {
                                 error!("✨ Indexing failed: {}", err);
                             },
                         }
+                        // The head block cannot be skipped, so start again from the new head.
+                        break Err(error);
                     },
                 };
             }
+            Some(result) = OptionFuture::from(best_future.as_mut()), if is_following_head && best_future.is_some() => {
+                match result {
+                    Ok(blocks) => {
+                        // Blocks may have been finalized while the branch was being indexed.
+                        let blocks: Vec<_> = blocks
+                            .into_iter()
+                            .filter(|(block_number, ..)| *block_number > current_span.end && index_range.end.is_none_or(|end| *block_number <= end))
+                            .collect();
+                        // Best blocks from the start of the branch onwards have been replaced.
+                        if let Some((block_number, ..)) = blocks.first() {
+                            indexer.retract_best_blocks(*block_number)?;
+                        }
+                        for (block_number, block_hash, event_count, key_count, block_batch) in blocks {
+                            indexer.commit_best_block(block_number, block_hash, block_batch)?;
+                            info!(
+                                "🌱 #{}: {} events, {} keys",
+                                block_number.to_formatted_string(&Locale::en),
+                                event_count.to_formatted_string(&Locale::en),
+                                key_count.to_formatted_string(&Locale::en),
+                            );
+                        }
+                    },
+                    Err(IndexError::SubscriptionClosed) => {
+                        error!("🌱 Best block subscription closed");
+                        break Err(IndexError::SubscriptionClosed);
+                    },
+                    Err(error) => {
+                        error!("🌱 Best block indexing failed: {}", error);
+                    },
+                }
+                drop(best_future);
+                best_future = best_sub
+                    .as_mut()
+                    .map(|best_sub| Box::pin(indexer.index_best_head(best_sub.next(), current_span.end)));
+            }
             _ = interval.tick(), if is_batching => {
                 let current_time = Instant::now();
                 let duration = (current_time.duration_since(stats_start_time)).as_micros();
                 if duration != 0 {
+                    let blocks_per_sec = <u32 as Into<u128>>::into(stats_block_count) * 1_000_000 / duration;
                     info!(
                         "📚 #{}: {} blocks/sec, {} events/sec, {} keys/sec",
                         current_span.start.to_formatted_string(&Locale::en),
-                        (<u32 as Into<u128>>::into(stats_block_count) * 1_000_000 / duration).to_formatted_string(&Locale::en),
+                        blocks_per_sec.to_formatted_string(&Locale::en),
                         (<u32 as Into<u128>>::into(stats_event_count) * 1_000_000 / duration).to_formatted_string(&Locale::en),
                         (<u32 as Into<u128>>::into(stats_key_count) * 1_000_000 / duration).to_formatted_string(&Locale::en),
                     );
+                    if let Some(queue_depth_controller) = queue_depth_controller.as_mut() {
+                        let depth = queue_depth_controller.update(indexer.take_batch_stats(), blocks_per_sec);
+                        if depth != queue_depth {
+                            debug!("📚 Queue depth: {}", depth);
+                            queue_depth = depth;
+                        }
+                        // Shrinking takes effect as blocks finish.
+                        while futures.len() < queue_depth.try_into().unwrap() {
+                            let Some(block_number) = take_next_batch_block(&spans, &mut next_batch_block, min_block, &mut in_flight, reindex_task.as_ref().map(|(range, _)| range)) else {
+                                break;
+                            };
+                            futures.push(Box::pin(indexer.index_block_retry(block_number, retry)));
+                            debug!("⬆️  Block #{} queued.", block_number.to_formatted_string(&Locale::en));
+                        }
+                    }
                 }
                 stats_block_count = 0;
                 stats_event_count = 0;
@@ -469,47 +1331,100 @@ pub async fn substrate_index<R: RuntimeIndexer>(
                 stats_start_time = current_time;
             }
             (result, index, _) = future::select_all(&mut futures), if is_batching => {
+                in_flight.remove(&result.0);
                 match result {
-                    Ok((block_number, event_count, key_count)) => {
-                        // Is the new block contiguous to the current span or an orphan?
-                        if block_number == current_span.start - 1 {
-                            current_span.start = block_number;
-                            debug!("⬇️  Block #{} indexed.", block_number.to_formatted_string(&Locale::en));
-                            check_span(&trees.span, &mut spans, &mut current_span)?;
-                            // Check if any orphans are now contiguous.
-                            while orphans.contains_key(&(current_span.start - 1)) {
-                                current_span.start -= 1;
-                                orphans.remove(&current_span.start);
-                                debug!("➡️  Block #{} unorphaned.", current_span.start.to_formatted_string(&Locale::en));
-                                check_span(&trees.span, &mut spans, &mut current_span)?;
-                            }
+                    (_, Ok((block_number, event_count, key_count, mut block_batch))) => {
+                        // Blocks outside the retention window are discarded.
+                        if block_number < min_block {
+                            debug!("⬇️  Block #{} indexed outside the retention window.", block_number.to_formatted_string(&Locale::en));
+                        }
+                        // Blocks being re-indexed are queued again once their keys have been deleted.
+                        else if reindex_task.as_ref().is_some_and(|(range, _)| range.start <= block_number && block_number <= range.end) {
+                            debug!("⬇️  Block #{} indexed while its keys are deleted.", block_number.to_formatted_string(&Locale::en));
                         }
                         else {
-                            orphans.insert(block_number, ());
-                            debug!("⬇️  Block #{} indexed and orphaned.", block_number.to_formatted_string(&Locale::en));
+                            // Is the new block contiguous to the current span or an orphan?
+                            if current_span.start.checked_sub(1) == Some(block_number) {
+                                current_span.start = block_number;
+                                debug!("⬇️  Block #{} indexed.", block_number.to_formatted_string(&Locale::en));
+                                check_span(&trees.span, &mut block_batch.batch, &mut spans, &mut current_span);
+                                // Check if any orphans are now contiguous.
+                                while let Some(start) = current_span.start.checked_sub(1).and_then(|end| orphans.remove(&end)) {
+                                    block_batch.batch.remove(&trees.span, (current_span.start - 1).to_be_bytes());
+                                    debug!(
+                                        "➡️  Blocks #{} to #{} unorphaned.",
+                                        start.to_formatted_string(&Locale::en),
+                                        (current_span.start - 1).to_formatted_string(&Locale::en)
+                                    );
+                                    current_span.start = start;
+                                    check_span(&trees.span, &mut block_batch.batch, &mut spans, &mut current_span);
+                                }
+                                write_span::<R>(&trees.span, &mut block_batch.batch, &current_span, index_variant);
+                            }
+                            // Blocks queued again after a re-index may already have been indexed.
+                            else if !is_block_indexed(&current_span, &orphans, block_number) {
+                                add_orphan::<R>(&trees.span, &mut block_batch.batch, &mut orphans, block_number, index_variant);
+                                debug!("⬇️  Block #{} indexed and orphaned.", block_number.to_formatted_string(&Locale::en));
+                            }
+                            block_batch.batch.remove(&trees.failed, block_number.to_be_bytes());
+                            indexer.commit_block(block_batch)?;
+                            stats_block_count += 1;
+                            stats_event_count += event_count;
+                            stats_key_count += key_count;
                         }
-                        stats_block_count += 1;
-                        stats_event_count += event_count;
-                        stats_key_count += key_count;
                     },
-                    Err(error) => {
-                        match error {
-                            IndexError::BlockNotFound(block_number) => {
-                                error!("📚 Block not found #{}", block_number.to_formatted_string(&Locale::en));
-                                is_batching = false;
-                            },
-                            _ => {
-                                error!("📚 Batch indexing failed: {:?}", error);
-                                is_batching = false;
-                            },
+                    (block_number, Err(error)) => {
+                        error!("📚 Batch indexing failed for #{}: {:?}", block_number.to_formatted_string(&Locale::en), error);
+                        // The block is indexed again after a restart.
+                        if block_number >= min_block {
+                            trees.failed.insert(block_number.to_be_bytes(), [])?;
+                            indexer.notify_status_subscribers();
                         }
+                        // No endpoint has the block or its state, so neither do they have any block before it.
+                        if error.is_block_unavailable() && next_batch_block.is_some() {
+                            error!("📚 Block #{} is not available from any endpoint, stopping the backfill.", block_number.to_formatted_string(&Locale::en));
+                            next_batch_block = None;
+                        }
+                    }
+                }
+                if futures.len() > queue_depth.try_into().unwrap() {
+                    // The adaptive queue depth has shrunk.
+                    drop(futures.swap_remove(index));
+                }
+                else if let Some(block_number) = take_next_batch_block(&spans, &mut next_batch_block, min_block, &mut in_flight, reindex_task.as_ref().map(|(range, _)| range)) {
+                    futures[index] = Box::pin(indexer.index_block_retry(block_number, retry));
+                    debug!("⬆️  Block #{} queued.", block_number.to_formatted_string(&Locale::en));
+                }
+                else {
+                    // The backfill has reached the start of the range, the retention window or genesis.
+                    drop(futures.swap_remove(index));
+                    if futures.is_empty() {
+                        info!("📚 Finished indexing back to #{}", min_block.to_formatted_string(&Locale::en));
+                        is_batching = false;
                     }
                 }
-                check_next_batch_block(&spans, &mut next_batch_block);
-                futures[index] = Box::pin(indexer.index_block(next_batch_block));
-                debug!("⬆️  Block #{} queued.", next_batch_block.to_formatted_string(&Locale::en));
-                next_batch_block -= 1;
             }
         }
+    };
+    // Wait for the keys outside the retention window to be deleted.
+    if let Some(prune_task) = prune_task {
+        let _ = prune_task.await;
+    }
+    // The range being re-indexed is indexed again after a restart, as it is no longer in the spans.
+    if let Some((_, reindex_task)) = reindex_task {
+        let _ = reindex_task.await;
+    }
+    // Best blocks are indexed again after a restart.
+    indexer.delete_best_blocks(0)?;
+    if current_span.start <= current_span.end {
+        let mut batch = DbBatch::default();
+        write_span::<R>(&trees.span, &mut batch, &current_span, index_variant);
+        trees.root.apply_batch(batch)?;
+        info!(
+            "📚 Recording current indexed span from #{} to #{}",
+            current_span.start.to_formatted_string(&Locale::en),
+            current_span.end.to_formatted_string(&Locale::en)
+        );
     }
+    result
 }
@@ -1,14 +1,23 @@
+use crate::endpoints::*;
+use crate::fsck::*;
+use crate::merge::*;
+use crate::queue::*;
 use crate::shared::*;
+use crate::snapshot::*;
 use crate::substrate::*;
 use crate::websockets::*;
 use crate::*;
 
 use hex_literal::hex;
 use serde::{Deserialize, Serialize};
-use sled::{Db, Tree};
-use std::str::FromStr;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+    time::Duration,
+};
 use subxt::utils::AccountId32;
 use tokio::sync::mpsc::{error::TryRecvError, unbounded_channel};
+use tokio_tungstenite::tungstenite;
 use zerocopy::{AsBytes, FromBytes};
 
 pub struct TestIndexer;
@@ -20,14 +29,14 @@ pub struct ChainTrees {
 }
 
 impl IndexTrees for ChainTrees {
-    fn open(db: &Db) -> Result<Self, sled::Error> {
+    fn open(db: &Db) -> Result<Self, StorageError> {
         Ok(ChainTrees {
             test_index: db.open_tree(b"test_index")?,
             test_hash: db.open_tree(b"candiate_hash")?,
         })
     }
 
-    fn flush(&self) -> Result<(), sled::Error> {
+    fn flush(&self) -> Result<(), StorageError> {
         self.test_index.flush()?;
         self.test_hash.flush()?;
         Ok(())
@@ -47,9 +56,10 @@ impl IndexKey for ChainKey {
     fn write_db_key(
         &self,
         trees: &ChainTrees,
+        batch: &mut DbBatch,
         block_number: u32,
         event_index: u16,
-    ) -> Result<(), sled::Error> {
+    ) {
         let block_number = block_number.into();
         let event_index = event_index.into();
         match self {
@@ -59,7 +69,7 @@ impl IndexKey for ChainKey {
                     block_number,
                     event_index,
                 };
-                trees.test_index.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.test_index, key.as_bytes(), [])
             }
             ChainKey::TestHash(test_hash) => {
                 let key = Bytes32Key {
@@ -67,16 +77,21 @@ impl IndexKey for ChainKey {
                     block_number,
                     event_index,
                 };
-                trees.test_hash.insert(key.as_bytes(), &[])?
+                batch.insert(&trees.test_hash, key.as_bytes(), [])
             }
         };
-        Ok(())
     }
 
-    fn get_key_events(&self, trees: &ChainTrees) -> Vec<Event> {
+    fn get_key_events(
+        &self,
+        trees: &ChainTrees,
+        query: &EventQuery,
+    ) -> (Vec<Event>, Option<Event>) {
         match self {
-            ChainKey::TestIndex(test_index) => get_events_u32(&trees.test_index, *test_index),
-            ChainKey::TestHash(test_hash) => get_events_bytes32(&trees.test_hash, test_hash),
+            ChainKey::TestIndex(test_index) => {
+                get_events_u32(&trees.test_index, *test_index, query)
+            }
+            ChainKey::TestHash(test_hash) => get_events_bytes32(&trees.test_hash, test_hash, query),
         }
     }
 }
@@ -145,8 +160,7 @@ impl RuntimeIndexer for TestIndexer2 {
 
 #[tokio::test]
 async fn test_process_msg_status() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
 
     let value = SpanDbValue {
         start: 0_u32.try_into().unwrap(),
@@ -178,11 +192,20 @@ async fn test_process_msg_status() {
         .insert(52_u32.to_be_bytes(), value.as_bytes())
         .unwrap();
 
-    let response = process_msg_status::<TestIndexer>(&trees.span);
+    let response = process_msg_status::<TestIndexer>(&trees);
 
-    let ResponseMessage::Status(spans) = response else {
+    let ResponseMessage::Status {
+        spans,
+        target,
+        failed,
+        failed_count,
+    } = response
+    else {
         panic!("Wrong response message.");
     };
+    assert_eq!(target, None);
+    assert!(failed.is_empty());
+    assert_eq!(failed_count, 0);
     assert_eq!(spans.len(), 3);
     assert_eq!(spans[0].start, 0);
     assert_eq!(spans[0].end, 40);
@@ -190,12 +213,105 @@ async fn test_process_msg_status() {
     assert_eq!(spans[1].end, 52);
     assert_eq!(spans[2].start, 60);
     assert_eq!(spans[2].end, 92);
+
+    let index_range = IndexRange {
+        start: 20,
+        end: Some(100),
+        backfill: false,
+    };
+    index_range.write(&trees.root).unwrap();
+    let ResponseMessage::Status { target, .. } = process_msg_status::<TestIndexer>(&trees) else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(target, Some(index_range));
+}
+
+#[test]
+fn test_failed_blocks() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    assert!(read_failed_blocks(&trees.failed, MAX_FAILED_BLOCKS)
+        .unwrap()
+        .is_empty());
+
+    for block_number in [12_u32, 5, 40] {
+        trees.failed.insert(block_number.to_be_bytes(), []).unwrap();
+    }
+    assert_eq!(
+        read_failed_blocks(&trees.failed, MAX_FAILED_BLOCKS).unwrap(),
+        vec![5, 12, 40]
+    );
+    assert_eq!(read_failed_blocks(&trees.failed, 2).unwrap(), vec![5, 12]);
+
+    let ResponseMessage::Status {
+        failed,
+        failed_count,
+        ..
+    } = process_msg_status::<TestIndexer>(&trees)
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(failed, vec![5, 12, 40]);
+    assert_eq!(failed_count, 3);
+
+    // The status only lists the first failed blocks.
+    for block_number in 100..300_u32 {
+        trees.failed.insert(block_number.to_be_bytes(), []).unwrap();
+    }
+    let ResponseMessage::Status {
+        failed,
+        failed_count,
+        ..
+    } = process_msg_status::<TestIndexer>(&trees)
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(failed.len(), MAX_FAILED_BLOCKS);
+    assert_eq!(failed[..3], [5, 12, 40]);
+    assert_eq!(failed_count, 203);
+
+    remove_failed_blocks(
+        &trees.failed,
+        &Span {
+            start: 10,
+            end: 299,
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        read_failed_blocks(&trees.failed, MAX_FAILED_BLOCKS).unwrap(),
+        vec![5]
+    );
+}
+
+#[test]
+fn test_block_unavailable() {
+    assert!(IndexError::BlockNotFound(5).is_block_unavailable());
+    assert!(!IndexError::SubscriptionClosed.is_block_unavailable());
+    assert!(
+        !IndexError::Subxt(subxt::Error::Other("connection reset".into())).is_block_unavailable()
+    );
+    let rpc_error = |code, message: &str| {
+        IndexError::Subxt(subxt::Error::Rpc(subxt::error::RpcError::ClientError(
+            Box::new(jsonrpsee_core::ClientError::Call(
+                serde_json::from_value(serde_json::json!({"code": code, "message": message}))
+                    .unwrap(),
+            )),
+        )))
+    };
+    assert!(
+        rpc_error(4003, "State already discarded for BlockId::Hash(0x00)").is_block_unavailable()
+    );
+    assert!(!rpc_error(-32603, "Internal error").is_block_unavailable());
+    // The message of other errors is not matched.
+    assert!(
+        !IndexError::Subxt(subxt::Error::Other("State already discarded".into()))
+            .is_block_unavailable()
+    );
 }
 
 #[tokio::test]
 async fn test_process_msg_subscribe_status() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let (sub_tx, mut sub_rx) = unbounded_channel();
     let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
@@ -210,7 +326,7 @@ async fn test_process_msg_subscribe_status() {
         .insert(40_u32.to_be_bytes(), value.as_bytes())
         .unwrap();
 
-    let response = process_msg_subscribe_status::<TestIndexer>(&sub_tx, &sub_response_tx);
+    let response = process_msg_subscribe_status::<TestIndexer>(Some(3), &sub_tx, &sub_response_tx);
 
     let ResponseMessage::Subscribed = response else {
         panic!("Wrong response message.");
@@ -220,9 +336,13 @@ async fn test_process_msg_subscribe_status() {
     process_sub_msg(&indexer, msg);
     indexer.notify_status_subscribers();
 
-    let response_msg = sub_response_rx.recv().await.unwrap();
+    let Response {
+        id,
+        msg: response_msg,
+    } = sub_response_rx.recv().await.unwrap();
+    assert_eq!(id, Some(3));
 
-    let ResponseMessage::Status(spans) = response_msg else {
+    let ResponseMessage::Status { spans, .. } = response_msg else {
         panic!("Wrong response message.");
     };
     assert_eq!(spans.len(), 1);
@@ -241,9 +361,13 @@ async fn test_process_msg_subscribe_status() {
 
     indexer.notify_status_subscribers();
 
-    let response_msg = sub_response_rx.recv().await.unwrap();
+    let Response {
+        id,
+        msg: response_msg,
+    } = sub_response_rx.recv().await.unwrap();
+    assert_eq!(id, Some(3));
 
-    let ResponseMessage::Status(spans) = response_msg else {
+    let ResponseMessage::Status { spans, .. } = response_msg else {
         panic!("Wrong response message.");
     };
     assert_eq!(spans.len(), 2);
@@ -264,9 +388,13 @@ async fn test_process_msg_subscribe_status() {
 
     indexer.notify_status_subscribers();
 
-    let response_msg = sub_response_rx.recv().await.unwrap();
+    let Response {
+        id,
+        msg: response_msg,
+    } = sub_response_rx.recv().await.unwrap();
+    assert_eq!(id, Some(3));
 
-    let ResponseMessage::Status(spans) = response_msg else {
+    let ResponseMessage::Status { spans, .. } = response_msg else {
         panic!("Wrong response message.");
     };
     assert_eq!(spans.len(), 3);
@@ -309,19 +437,20 @@ fn test_variant_key() {
 
 #[tokio::test]
 async fn test_process_msg_variant() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let key = Key::Variant(3, 65);
     indexer.index_event(key.clone(), 4, 5).unwrap();
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -349,8 +478,7 @@ fn test_bytes32_key() {
 
 #[tokio::test]
 async fn test_process_msg_account_id() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let account_id =
         AccountId32::from_str("5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY").unwrap();
@@ -359,11 +487,13 @@ async fn test_process_msg_account_id() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -389,8 +519,7 @@ fn test_u32_key() {
 
 #[tokio::test]
 async fn test_process_msg_account_index() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let account_index = 88;
     let key = Key::Substrate(SubstrateKey::AccountIndex(account_index));
@@ -398,11 +527,13 @@ async fn test_process_msg_account_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -416,8 +547,7 @@ async fn test_process_msg_account_index() {
 
 #[tokio::test]
 async fn test_process_msg_bounty_index() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let bounty_index = 88;
     let key = Key::Substrate(SubstrateKey::BountyIndex(bounty_index));
@@ -425,11 +555,13 @@ async fn test_process_msg_bounty_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -443,8 +575,7 @@ async fn test_process_msg_bounty_index() {
 
 #[tokio::test]
 async fn test_process_msg_era_index() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let era_index = 88;
     let key = Key::Substrate(SubstrateKey::EraIndex(era_index));
@@ -452,11 +583,13 @@ async fn test_process_msg_era_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -470,8 +603,7 @@ async fn test_process_msg_era_index() {
 
 #[tokio::test]
 async fn test_process_msg_message_id() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let message_id = Bytes32([8; 32]);
     let key = Key::Substrate(SubstrateKey::MessageId(message_id));
@@ -479,11 +611,13 @@ async fn test_process_msg_message_id() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -497,8 +631,7 @@ async fn test_process_msg_message_id() {
 
 #[tokio::test]
 async fn test_process_msg_pool_id() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let pool_id = 88;
     let key = Key::Substrate(SubstrateKey::PoolId(pool_id));
@@ -506,11 +639,13 @@ async fn test_process_msg_pool_id() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -524,8 +659,7 @@ async fn test_process_msg_pool_id() {
 
 #[tokio::test]
 async fn test_process_msg_preimage_hash() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let preimage_hash = Bytes32([8; 32]);
     let key = Key::Substrate(SubstrateKey::PreimageHash(preimage_hash));
@@ -533,11 +667,13 @@ async fn test_process_msg_preimage_hash() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -551,8 +687,7 @@ async fn test_process_msg_preimage_hash() {
 
 #[tokio::test]
 async fn test_process_msg_proposal_hash() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let proposal_hash = Bytes32([8; 32]);
     let key = Key::Substrate(SubstrateKey::ProposalHash(proposal_hash));
@@ -560,11 +695,13 @@ async fn test_process_msg_proposal_hash() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -578,8 +715,7 @@ async fn test_process_msg_proposal_hash() {
 
 #[tokio::test]
 async fn test_process_msg_proposal_index() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let proposal_index = 88;
     let key = Key::Substrate(SubstrateKey::ProposalIndex(proposal_index));
@@ -587,11 +723,13 @@ async fn test_process_msg_proposal_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -605,8 +743,7 @@ async fn test_process_msg_proposal_index() {
 
 #[tokio::test]
 async fn test_process_msg_ref_index() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let ref_index = 88;
     let key = Key::Substrate(SubstrateKey::RefIndex(ref_index));
@@ -614,11 +751,13 @@ async fn test_process_msg_ref_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -632,8 +771,7 @@ async fn test_process_msg_ref_index() {
 
 #[tokio::test]
 async fn test_process_msg_registrar_index() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let registrar_index = 88;
     let key = Key::Substrate(SubstrateKey::RegistrarIndex(registrar_index));
@@ -641,11 +779,13 @@ async fn test_process_msg_registrar_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -659,8 +799,7 @@ async fn test_process_msg_registrar_index() {
 
 #[tokio::test]
 async fn test_process_msg_session_index() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let session_index = 88;
     let key = Key::Substrate(SubstrateKey::SessionIndex(session_index));
@@ -668,11 +807,13 @@ async fn test_process_msg_session_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -686,8 +827,7 @@ async fn test_process_msg_session_index() {
 
 #[tokio::test]
 async fn test_process_msg_tip_hash() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let tip_hash = Bytes32([8; 32]);
     let key = Key::Substrate(SubstrateKey::TipHash(tip_hash));
@@ -695,11 +835,13 @@ async fn test_process_msg_tip_hash() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -713,8 +855,7 @@ async fn test_process_msg_tip_hash() {
 
 #[tokio::test]
 async fn test_process_msg_chain_test_index() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let test_index = 88;
     let key = Key::Chain(ChainKey::TestIndex(test_index));
@@ -722,11 +863,13 @@ async fn test_process_msg_chain_test_index() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -740,8 +883,7 @@ async fn test_process_msg_chain_test_index() {
 
 #[tokio::test]
 async fn test_process_msg_chain_test_hash() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
     let test_hash = Bytes32([8; 32]);
     let key = Key::Chain(ChainKey::TestHash(test_hash));
@@ -749,11 +891,13 @@ async fn test_process_msg_chain_test_hash() {
     indexer.index_event(key.clone(), 8, 5).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
 
-    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone());
+    let response =
+        process_msg_get_events::<TestIndexer>(&trees, key.clone(), &EventQuery::default());
 
     let ResponseMessage::Events {
         key: response_key,
         events,
+        ..
     } = response
     else {
         panic!("Wrong response message.");
@@ -766,338 +910,2228 @@ async fn test_process_msg_chain_test_hash() {
 }
 
 #[tokio::test]
-async fn test_process_msg_subscribe_events() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
+async fn test_process_msg_get_events_paginated() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
     let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
-    let (sub_tx, mut sub_rx) = unbounded_channel();
-    let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
-    let key = Key::Variant(3, 65);
+    let key = Key::Substrate(SubstrateKey::AccountId(Bytes32([8; 32])));
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 2).unwrap();
+    indexer.index_event(key.clone(), 10, 5).unwrap();
+    indexer.index_event(key.clone(), 12, 5).unwrap();
+
+    let request_json = r#"{"id":1,"type":"GetEvents","key":{"type":"Substrate","value":{"type":"AccountId","value":"0x0808080808080808080808080808080808080808080808080808080808080808"}},"limit":2}"#;
+    let Request {
+        id,
+        msg: RequestMessage::GetEvents {
+            key: request_key,
+            query,
+        },
+    } = serde_json::from_str::<Request<ChainKey>>(request_json).unwrap()
+    else {
+        panic!("Wrong request message.");
+    };
+    assert_eq!(id, Some(1));
+    assert_eq!(key, request_key);
+    assert_eq!(query.cursor, None);
+    assert_eq!(query.limit, Some(2));
 
-    let response =
-        process_msg_subscribe_events::<TestIndexer>(key.clone(), &sub_tx, &sub_response_tx);
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), &query);
 
-    let ResponseMessage::Subscribed = response else {
+    let ResponseMessage::Events {
+        events,
+        next_cursor,
+        ..
+    } = response
+    else {
         panic!("Wrong response message.");
     };
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].block_number, 12);
+    assert_eq!(events[1].block_number, 10);
+    assert_eq!(next_cursor, Some(events[1].clone()));
+
+    let query = EventQuery {
+        cursor: next_cursor,
+        limit: Some(2),
+        ..Default::default()
+    };
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), &query);
 
-    let msg = sub_rx.recv().await.unwrap();
-    process_sub_msg(&indexer, msg);
-
-    indexer.index_event(key.clone(), 4, 5).unwrap();
-
-    let response_msg = sub_response_rx.recv().await.unwrap();
     let ResponseMessage::Events {
-        key: response_key,
         events,
-    } = response_msg
+        next_cursor,
+        ..
+    } = response
     else {
         panic!("Wrong response message.");
     };
-    assert_eq!(key, response_key);
-    assert_eq!(events.len(), 1);
-    assert_eq!(events[0].block_number, 4);
+    assert_eq!(events.len(), 2);
+    assert_eq!(
+        events[0],
+        Event {
+            block_number: 8,
+            event_index: 5
+        }
+    );
+    assert_eq!(
+        events[1],
+        Event {
+            block_number: 8,
+            event_index: 2
+        }
+    );
+    assert_eq!(next_cursor, Some(events[1].clone()));
 
-    indexer.index_event(key.clone(), 8, 5).unwrap();
+    let query = EventQuery {
+        cursor: next_cursor,
+        limit: Some(2),
+        ..Default::default()
+    };
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), &query);
 
-    let response_msg = sub_response_rx.recv().await.unwrap();
     let ResponseMessage::Events {
-        key: response_key,
         events,
-    } = response_msg
+        next_cursor,
+        ..
+    } = response
     else {
         panic!("Wrong response message.");
     };
-    assert_eq!(key, response_key);
     assert_eq!(events.len(), 1);
-    assert_eq!(events[0].block_number, 8);
+    assert_eq!(events[0].block_number, 4);
+    assert_eq!(next_cursor, None);
+}
 
+#[tokio::test]
+async fn test_process_msg_get_events_block_range() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Substrate(SubstrateKey::PoolId(7));
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 2).unwrap();
     indexer.index_event(key.clone(), 10, 5).unwrap();
+    indexer.index_event(key.clone(), 12, 5).unwrap();
+    indexer
+        .index_event(Key::Substrate(SubstrateKey::PoolId(8)), 9, 1)
+        .unwrap();
+
+    let query = EventQuery {
+        from_block: Some(8),
+        to_block: Some(10),
+        ..Default::default()
+    };
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), &query);
 
-    let response_msg = sub_response_rx.recv().await.unwrap();
     let ResponseMessage::Events {
-        key: response_key,
         events,
-    } = response_msg
+        next_cursor,
+        ..
+    } = response
     else {
         panic!("Wrong response message.");
     };
-    assert_eq!(key, response_key);
-    assert_eq!(events.len(), 1);
+    assert_eq!(events.len(), 3);
     assert_eq!(events[0].block_number, 10);
+    assert_eq!(events[1].block_number, 8);
+    assert_eq!(events[1].event_index, 5);
+    assert_eq!(events[2].block_number, 8);
+    assert_eq!(events[2].event_index, 2);
+    assert_eq!(next_cursor, None);
+
+    let query = EventQuery {
+        cursor: Some(Event {
+            block_number: 8,
+            event_index: 5,
+        }),
+        from_block: Some(5),
+        to_block: Some(10),
+        ..Default::default()
+    };
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), &query);
 
-    let response =
-        process_msg_unsubscribe_events::<TestIndexer>(key.clone(), &sub_tx, &sub_response_tx);
-
-    let ResponseMessage::Unsubscribed = response else {
+    let ResponseMessage::Events { events, .. } = response else {
         panic!("Wrong response message.");
     };
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 8);
+    assert_eq!(events[0].event_index, 2);
 
-    let msg = sub_rx.recv().await.unwrap();
-    process_sub_msg(&indexer, msg);
-
-    indexer.index_event(key.clone(), 18, 5).unwrap();
-
-    let response_msg = sub_response_rx.try_recv();
+    let query = EventQuery {
+        from_block: Some(11),
+        to_block: Some(10),
+        ..Default::default()
+    };
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), &query);
 
-    let Err(TryRecvError::Empty) = response_msg else {
+    let ResponseMessage::Events { events, .. } = response else {
         panic!("Wrong response message.");
     };
+    assert_eq!(events.len(), 0);
 }
 
-#[test]
-fn test_load_spans() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
-    trees.span.clear().unwrap();
-    let spans = load_spans::<TestIndexer>(&trees.span, false).unwrap();
-    assert_eq!(trees.span.len(), 0);
-    assert_eq!(spans.len(), 0);
-    let value = SpanDbValue {
-        start: 80_u32.into(),
-        version: 0_u16.into(),
-        index_variant: 0,
+#[tokio::test]
+async fn test_process_msg_get_events_ascending() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Variant(3, 65);
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+    indexer.index_event(key.clone(), 8, 2).unwrap();
+    indexer.index_event(key.clone(), 8, 5).unwrap();
+    indexer.index_event(key.clone(), 10, 5).unwrap();
+    indexer.index_event(key.clone(), 12, 5).unwrap();
+    indexer.index_event(Key::Variant(3, 64), 2, 1).unwrap();
+
+    let request_json = r#"{"type":"GetEvents","key":{"type":"Variant","value":[3,65]},"limit":2,"order":"Ascending"}"#;
+    let RequestMessage::GetEvents { query, .. } =
+        serde_json::from_str::<RequestMessage<ChainKey>>(request_json).unwrap()
+    else {
+        panic!("Wrong request message.");
     };
-    trees
-        .span
-        .insert(100_u32.to_be_bytes(), value.as_bytes())
-        .unwrap();
-    let spans = load_spans::<TestIndexer>(&trees.span, false).unwrap();
-    assert_eq!(trees.span.len(), 1);
-    assert_eq!(spans.len(), 1);
-    assert_eq!(
-        spans[0],
-        Span {
-            start: 80,
-            end: 100
-        }
-    );
-    let value = SpanDbValue {
-        start: 180_u32.into(),
-        version: 0_u16.into(),
-        index_variant: 0,
+    assert_eq!(query.order, Order::Ascending);
+
+    let response = process_msg_get_events::<TestIndexer>(&trees, key.clone(), &query);
+
+    let ResponseMessage::Events {
+        events,
+        next_cursor,
+        ..
+    } = response
+    else {
+        panic!("Wrong response message.");
     };
-    trees
-        .span
-        .insert(200_u32.to_be_bytes(), value.as_bytes())
-        .unwrap();
-    let spans = load_spans::<TestIndexer>(&trees.span, false).unwrap();
-    assert_eq!(trees.span.len(), 2);
-    assert_eq!(spans.len(), 2);
-    assert_eq!(
-        spans[0],
-        Span {
-            start: 80,
-            end: 100
-        }
-    );
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].block_number, 4);
     assert_eq!(
-        spans[1],
-        Span {
-            start: 180,
-            end: 200
+        events[1],
+        Event {
+            block_number: 8,
+            event_index: 2
         }
     );
-    let spans = load_spans::<TestIndexer2>(&trees.span, false).unwrap();
-    assert_eq!(trees.span.len(), 2);
-    assert_eq!(spans.len(), 2);
+    assert_eq!(next_cursor, Some(events[1].clone()));
+
+    let query = EventQuery {
+        cursor: next_cursor,
+        to_block: Some(11),
+        order: Order::Ascending,
+        ..Default::default()
+    };
+    let (events, next_cursor) = get_key_events::<TestIndexer>(&trees, &key, &query);
+    assert_eq!(events.len(), 2);
     assert_eq!(
-        spans[0],
-        Span {
-            start: 80,
-            end: 100
+        events[0],
+        Event {
+            block_number: 8,
+            event_index: 5
         }
     );
+    assert_eq!(events[1].block_number, 10);
+    assert_eq!(next_cursor, None);
+
+    let query = EventQuery {
+        cursor: Some(Event {
+            block_number: 2,
+            event_index: 0,
+        }),
+        from_block: Some(5),
+        order: Order::Ascending,
+        ..Default::default()
+    };
+    let (events, _) = get_key_events::<TestIndexer>(&trees, &key, &query);
+    assert_eq!(events.len(), 4);
+    assert_eq!(events[0].block_number, 8);
+
+    let other_key = Key::Chain(ChainKey::TestIndex(1));
+    indexer.index_event(other_key.clone(), 8, 5).unwrap();
+    indexer.index_event(other_key.clone(), 12, 5).unwrap();
+    let expression = KeyExpression::And(vec![
+        KeyExpression::Key(key.clone()),
+        KeyExpression::Key(other_key.clone()),
+    ]);
+    let query = EventQuery {
+        order: Order::Ascending,
+        ..Default::default()
+    };
+    let (events, _) = get_expression_events::<TestIndexer>(&trees, &expression, &query);
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].block_number, 8);
+    assert_eq!(events[1].block_number, 12);
+}
+
+#[tokio::test]
+async fn test_process_msg_get_expression_events() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let account_key = Key::Substrate(SubstrateKey::AccountId(Bytes32([8; 32])));
+    let pool_key = Key::Substrate(SubstrateKey::PoolId(7));
+    let variant_key = Key::Variant(5, 2);
+    indexer.index_event(account_key.clone(), 4, 5).unwrap();
+    indexer.index_event(account_key.clone(), 8, 5).unwrap();
+    indexer.index_event(account_key.clone(), 10, 1).unwrap();
+    indexer.index_event(account_key.clone(), 12, 5).unwrap();
+    indexer.index_event(pool_key.clone(), 8, 5).unwrap();
+    indexer.index_event(pool_key.clone(), 10, 2).unwrap();
+    indexer.index_event(pool_key.clone(), 12, 5).unwrap();
+    indexer.index_event(variant_key.clone(), 4, 5).unwrap();
+    indexer.index_event(variant_key.clone(), 12, 5).unwrap();
+
+    let request_json = r#"{"type":"GetExpressionEvents","expression":{"type":"And","value":[{"type":"Key","value":{"type":"Substrate","value":{"type":"AccountId","value":"0x0808080808080808080808080808080808080808080808080808080808080808"}}},{"type":"Key","value":{"type":"Substrate","value":{"type":"PoolId","value":7}}}]},"limit":1}"#;
+    let RequestMessage::GetExpressionEvents { expression, query } =
+        serde_json::from_str::<RequestMessage<ChainKey>>(request_json).unwrap()
+    else {
+        panic!("Wrong request message.");
+    };
     assert_eq!(
-        spans[1],
-        Span {
-            start: 180,
-            end: 200
-        }
+        expression,
+        KeyExpression::And(vec![
+            KeyExpression::Key(account_key.clone()),
+            KeyExpression::Key(pool_key.clone()),
+        ])
     );
-    let value = SpanDbValue {
-        start: 400_u32.into(),
-        version: 0_u16.into(),
-        index_variant: 0,
+
+    let response =
+        process_msg_get_expression_events::<TestIndexer>(&trees, expression.clone(), &[], &query)
+            .unwrap();
+
+    let ResponseMessage::ExpressionEvents {
+        events,
+        next_cursor,
+        ..
+    } = response
+    else {
+        panic!("Wrong response message.");
     };
-    trees
-        .span
-        .insert(600_u32.to_be_bytes(), value.as_bytes())
-        .unwrap();
-    let spans = load_spans::<TestIndexer2>(&trees.span, false).unwrap();
-    assert_eq!(trees.span.len(), 3);
-    assert_eq!(spans.len(), 3);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 12);
+    assert_eq!(next_cursor, Some(events[0].clone()));
+
+    let query = EventQuery {
+        cursor: next_cursor,
+        ..Default::default()
+    };
+    let (events, next_cursor) = get_expression_events::<TestIndexer>(&trees, &expression, &query);
+    assert_eq!(events.len(), 1);
     assert_eq!(
-        spans[0],
-        Span {
-            start: 80,
-            end: 100
+        events[0],
+        Event {
+            block_number: 8,
+            event_index: 5
         }
     );
+    assert_eq!(next_cursor, None);
+
+    let expression = KeyExpression::Or(vec![
+        KeyExpression::Key(account_key.clone()),
+        KeyExpression::Key(pool_key.clone()),
+    ]);
+    let (events, _) =
+        get_expression_events::<TestIndexer>(&trees, &expression, &EventQuery::default());
+    assert_eq!(events.len(), 5);
+    assert_eq!(events[0].block_number, 12);
     assert_eq!(
-        spans[1],
-        Span {
-            start: 180,
-            end: 200
+        events[1],
+        Event {
+            block_number: 10,
+            event_index: 2
         }
     );
     assert_eq!(
-        spans[2],
-        Span {
-            start: 400,
-            end: 499
+        events[2],
+        Event {
+            block_number: 10,
+            event_index: 1
         }
     );
-    let value = SpanDbValue {
-        start: 500_u32.into(),
-        version: 0_u16.into(),
-        index_variant: 0,
-    };
-    trees
-        .span
-        .insert(600_u32.to_be_bytes(), value.as_bytes())
-        .unwrap();
-    let spans = load_spans::<TestIndexer2>(&trees.span, false).unwrap();
-    assert_eq!(trees.span.len(), 3);
-    assert_eq!(spans.len(), 3);
-    assert_eq!(
-        spans[0],
-        Span {
-            start: 80,
-            end: 100
-        }
+    assert_eq!(events[3].block_number, 8);
+    assert_eq!(events[4].block_number, 4);
+
+    let expression = KeyExpression::And(vec![
+        KeyExpression::Key(variant_key.clone()),
+        KeyExpression::Or(vec![
+            KeyExpression::Key(account_key.clone()),
+            KeyExpression::Key(pool_key.clone()),
+        ]),
+    ]);
+    let (events, _) =
+        get_expression_events::<TestIndexer>(&trees, &expression, &EventQuery::default());
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].block_number, 12);
+    assert_eq!(events[1].block_number, 4);
+
+    let (events, _) = get_expression_events::<TestIndexer>(
+        &trees,
+        &KeyExpression::Or(vec![]),
+        &EventQuery::default(),
     );
-    assert_eq!(
+    assert_eq!(events.len(), 0);
+}
+
+#[tokio::test]
+async fn test_process_msg_get_events_batch() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key1 = Key::Substrate(SubstrateKey::AccountId(Bytes32([1; 32])));
+    let key2 = Key::Substrate(SubstrateKey::AccountId(Bytes32([2; 32])));
+    let key3 = Key::Substrate(SubstrateKey::AccountId(Bytes32([3; 32])));
+    indexer.index_event(key1.clone(), 4, 5).unwrap();
+    indexer.index_event(key1.clone(), 8, 5).unwrap();
+    indexer.index_event(key2.clone(), 8, 5).unwrap();
+    indexer.index_event(key2.clone(), 10, 5).unwrap();
+
+    let keys = vec![key1.clone(), key2.clone(), key3.clone()];
+    let response = process_msg_get_events_batch::<TestIndexer>(
+        &trees,
+        keys.clone(),
+        false,
+        &EventQuery::default(),
+    )
+    .unwrap();
+
+    let ResponseMessage::EventsBatch(batch) = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(batch.len(), 3);
+    assert_eq!(batch[0].key, key1);
+    assert_eq!(batch[0].events.len(), 2);
+    assert_eq!(batch[0].events[0].block_number, 8);
+    assert_eq!(batch[0].events[1].block_number, 4);
+    assert_eq!(batch[1].key, key2);
+    assert_eq!(batch[1].events.len(), 2);
+    assert_eq!(batch[1].events[0].block_number, 10);
+    assert_eq!(batch[1].events[1].block_number, 8);
+    assert_eq!(batch[2].key, key3);
+    assert_eq!(batch[2].events.len(), 0);
+
+    let response = process_msg_get_events_batch::<TestIndexer>(
+        &trees,
+        keys.clone(),
+        true,
+        &EventQuery::default(),
+    )
+    .unwrap();
+
+    let ResponseMessage::ExpressionEvents { events, .. } = response else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].block_number, 10);
+    assert_eq!(events[1].block_number, 8);
+    assert_eq!(events[2].block_number, 4);
+
+    let keys = vec![key1.clone(); MAX_BATCH_KEYS + 1];
+    let Err(IndexError::LimitExceeded { limit, max }) =
+        process_msg_get_events_batch::<TestIndexer>(&trees, keys, false, &EventQuery::default())
+    else {
+        panic!("Wrong result.");
+    };
+    assert_eq!(limit, MAX_BATCH_KEYS + 1);
+    assert_eq!(max, MAX_BATCH_KEYS);
+}
+
+#[tokio::test]
+async fn test_process_msg_get_expression_events_variant_name() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    indexer.index_event(Key::Variant(5, 2), 4, 5).unwrap();
+    indexer.index_event(Key::Variant(6, 2), 8, 5).unwrap();
+    indexer.index_event(Key::Variant(6, 3), 10, 5).unwrap();
+    // The Balances pallet index changes in the second runtime version.
+    let versions = vec![
+        vec![PalletMeta {
+            index: 5,
+            name: "Balances".to_owned(),
+            events: vec![EventMeta {
+                index: 2,
+                name: "Transfer".to_owned(),
+            }],
+        }],
+        vec![PalletMeta {
+            index: 6,
+            name: "Balances".to_owned(),
+            events: vec![
+                EventMeta {
+                    index: 2,
+                    name: "Transfer".to_owned(),
+                },
+                EventMeta {
+                    index: 3,
+                    name: "Reserved".to_owned(),
+                },
+            ],
+        }],
+    ];
+
+    let request_json = r#"{"type":"GetExpressionEvents","expression":{"type":"VariantName","value":{"pallet":"Balances","event":"Transfer"}}}"#;
+    let RequestMessage::GetExpressionEvents { expression, query } =
+        serde_json::from_str::<RequestMessage<ChainKey>>(request_json).unwrap()
+    else {
+        panic!("Wrong request message.");
+    };
+    assert_eq!(
+        resolve_expression(&expression, &versions).unwrap(),
+        KeyExpression::Or(vec![
+            KeyExpression::Key(Key::Variant(5, 2)),
+            KeyExpression::Key(Key::Variant(6, 2)),
+        ])
+    );
+
+    let response = process_msg_get_expression_events::<TestIndexer>(
+        &trees,
+        expression.clone(),
+        &versions,
+        &query,
+    )
+    .unwrap();
+
+    let ResponseMessage::ExpressionEvents {
+        expression: response_expression,
+        events,
+        ..
+    } = response
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(expression, response_expression);
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].block_number, 8);
+    assert_eq!(events[1].block_number, 4);
+
+    let expression = KeyExpression::VariantName {
+        pallet: "Balances".to_owned(),
+        event: "Unknown".to_owned(),
+    };
+    let Err(IndexError::VariantNotFound { .. }) =
+        process_msg_get_expression_events::<TestIndexer>(&trees, expression, &versions, &query)
+    else {
+        panic!("Wrong result.");
+    };
+}
+
+#[tokio::test]
+async fn test_process_msg_count_events() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let key = Key::Chain(ChainKey::TestIndex(88));
+    for block_number in 0..2500 {
+        indexer.index_event(key.clone(), block_number, 5).unwrap();
+    }
+    indexer
+        .index_event(Key::Chain(ChainKey::TestIndex(89)), 10, 5)
+        .unwrap();
+
+    let request_json =
+        r#"{"type":"CountEvents","key":{"type":"Chain","value":{"type":"TestIndex","value":88}}}"#;
+    let RequestMessage::CountEvents {
+        key: request_key,
+        from_block,
+        to_block,
+    } = serde_json::from_str::<RequestMessage<ChainKey>>(request_json).unwrap()
+    else {
+        panic!("Wrong request message.");
+    };
+    assert_eq!(key, request_key);
+
+    let response =
+        process_msg_count_events::<TestIndexer>(&trees, key.clone(), from_block, to_block);
+
+    let ResponseMessage::EventCount {
+        key: response_key,
+        count,
+    } = response
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(key, response_key);
+    assert_eq!(count, 2500);
+
+    let count = count_key_events::<TestIndexer>(&trees, &key, Some(1000), Some(1099));
+    assert_eq!(count, 100);
+    let count = count_key_events::<TestIndexer>(&trees, &key, Some(2400), None);
+    assert_eq!(count, 100);
+}
+
+#[test]
+fn test_process_error() {
+    let msg = tungstenite::Message::Text("{\"type\":\"Status\"".into());
+    let error = parse_msg::<TestIndexer>(&msg).1.unwrap_err();
+    let ResponseMessage::Error { code, .. } = process_error::<TestIndexer>(error) else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(code, ErrorCode::ParseError);
+
+    let msg = tungstenite::Message::Text("{\"id\":7,\"type\":\"Unknown\"}".into());
+    let (id, request_msg) = parse_msg::<TestIndexer>(&msg);
+    assert_eq!(id, Some(7));
+    let error = request_msg.unwrap_err();
+    let ResponseMessage::Error { code, .. } = process_error::<TestIndexer>(error) else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(code, ErrorCode::ParseError);
+
+    let msg = tungstenite::Message::Text(
+        r#"{"type":"GetEvents","key":{"type":"Substrate","value":{"type":"Unknown","value":1}}}"#
+            .into(),
+    );
+    let error = parse_msg::<TestIndexer>(&msg).1.unwrap_err();
+    let ResponseMessage::Error { code, .. } = process_error::<TestIndexer>(error) else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(code, ErrorCode::UnknownKeyType);
+
+    // Variant names are only resolved in expressions.
+    for request_json in [
+        r#"{"type":"GetEvents","key":{"type":"VariantName","value":{"pallet":"Balances","event":"Transfer"}}}"#,
+        r#"{"type":"CountEvents","key":{"type":"VariantName","value":{"pallet":"Balances","event":"Transfer"}}}"#,
+        r#"{"type":"SubscribeEvents","key":{"type":"VariantName","value":{"pallet":"Balances","event":"Transfer"}}}"#,
+    ] {
+        let msg = tungstenite::Message::Text(request_json.into());
+        let error = parse_msg::<TestIndexer>(&msg).1.unwrap_err();
+        let ResponseMessage::Error { code, .. } = process_error::<TestIndexer>(error) else {
+            panic!("Wrong response message.");
+        };
+        assert_eq!(code, ErrorCode::UnknownKeyType);
+    }
+
+    let msg = tungstenite::Message::Text(
+        r#"{"type":"GetEvents","key":{"type":"Variant","value":[0,0]},"limit":1001}"#.into(),
+    );
+    let RequestMessage::GetEvents { query, .. } = parse_msg::<TestIndexer>(&msg).1.unwrap() else {
+        panic!("Wrong request message.");
+    };
+    let error = check_query(&query).unwrap_err();
+    let response = process_error::<TestIndexer>(error);
+    let ResponseMessage::Error { code, .. } = response.clone() else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(code, ErrorCode::LimitExceeded);
+    assert_eq!(
+        serde_json::to_string(&response).unwrap(),
+        r#"{"type":"error","data":{"code":"LimitExceeded","message":"limit exceeded: 1001 is more than 1000"}}"#
+    );
+    let response = Response {
+        id: Some(9),
+        msg: response,
+    };
+    assert_eq!(
+        serde_json::to_string(&response).unwrap(),
+        r#"{"id":9,"type":"error","data":{"code":"LimitExceeded","message":"limit exceeded: 1001 is more than 1000"}}"#
+    );
+
+    let error = IndexError::BlockNotFound(5);
+    let ResponseMessage::Error { code, .. } = process_error::<TestIndexer>(error) else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(code, ErrorCode::BackendUnavailable);
+
+    let ResponseMessage::Error { code, message } =
+        process_error::<TestIndexer>(IndexError::SubscriptionClosed)
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(code, ErrorCode::BackendUnavailable);
+    assert_eq!(message, "connection error");
+}
+
+#[tokio::test]
+async fn test_process_msg_subscribe_events() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let (sub_tx, mut sub_rx) = unbounded_channel();
+    let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
+    let key = Key::Variant(3, 65);
+
+    process_msg_subscribe_events::<TestIndexer>(
+        key.clone(),
+        None,
+        Some(4),
+        &sub_tx,
+        &sub_response_tx,
+    );
+
+    let msg = sub_rx.recv().await.unwrap();
+    process_sub_msg(&indexer, msg);
+
+    // The indexer thread acknowledges the subscription.
+    let Response {
+        id,
+        msg: ResponseMessage::Subscribed,
+    } = sub_response_rx.recv().await.unwrap()
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(id, Some(4));
+
+    indexer.index_event(key.clone(), 4, 5).unwrap();
+
+    let Response {
+        id,
+        msg: response_msg,
+    } = sub_response_rx.recv().await.unwrap();
+    assert_eq!(id, Some(4));
+    let ResponseMessage::Events {
+        key: response_key,
+        events,
+        next_cursor,
+    } = response_msg
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(key, response_key);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 4);
+    assert_eq!(next_cursor, None);
+
+    indexer.index_event(key.clone(), 8, 5).unwrap();
+
+    let Response {
+        id,
+        msg: response_msg,
+    } = sub_response_rx.recv().await.unwrap();
+    assert_eq!(id, Some(4));
+    let ResponseMessage::Events {
+        key: response_key,
+        events,
+        next_cursor,
+    } = response_msg
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(key, response_key);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 8);
+    assert_eq!(next_cursor, None);
+
+    indexer.index_event(key.clone(), 10, 5).unwrap();
+
+    let Response {
+        id,
+        msg: response_msg,
+    } = sub_response_rx.recv().await.unwrap();
+    assert_eq!(id, Some(4));
+    let ResponseMessage::Events {
+        key: response_key,
+        events,
+        next_cursor,
+    } = response_msg
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(key, response_key);
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 10);
+    assert_eq!(next_cursor, None);
+
+    let response =
+        process_msg_unsubscribe_events::<TestIndexer>(key.clone(), &sub_tx, &sub_response_tx);
+
+    let ResponseMessage::Unsubscribed = response else {
+        panic!("Wrong response message.");
+    };
+
+    let msg = sub_rx.recv().await.unwrap();
+    process_sub_msg(&indexer, msg);
+
+    indexer.index_event(key.clone(), 18, 5).unwrap();
+
+    let response_msg = sub_response_rx.try_recv();
+
+    let Err(TryRecvError::Empty) = response_msg else {
+        panic!("Wrong response message.");
+    };
+}
+
+#[tokio::test]
+async fn test_process_msg_subscribe_events_since() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let (sub_tx, mut sub_rx) = unbounded_channel();
+    let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
+    let key = Key::Substrate(SubstrateKey::AccountId(Bytes32([8; 32])));
+    for block_number in 0..1500 {
+        indexer.index_event(key.clone(), block_number, 5).unwrap();
+    }
+
+    let request_json = r#"{"type":"SubscribeEvents","key":{"type":"Substrate","value":{"type":"AccountId","value":"0x0808080808080808080808080808080808080808080808080808080808080808"}},"since":{"blockNumber":200,"eventIndex":5}}"#;
+    let RequestMessage::SubscribeEvents {
+        key: request_key,
+        since,
+    } = serde_json::from_str::<RequestMessage<ChainKey>>(request_json).unwrap()
+    else {
+        panic!("Wrong request message.");
+    };
+    assert_eq!(key, request_key);
+
+    process_msg_subscribe_events::<TestIndexer>(
+        key.clone(),
+        since,
+        Some(6),
+        &sub_tx,
+        &sub_response_tx,
+    );
+
+    let msg = sub_rx.recv().await.unwrap();
+    process_sub_msg(&indexer, msg);
+
+    // The subscription is acknowledged before the events are replayed.
+    let Response {
+        id,
+        msg: ResponseMessage::Subscribed,
+    } = sub_response_rx.recv().await.unwrap()
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(id, Some(6));
+
+    let Response {
+        id,
+        msg: ResponseMessage::Events { events, .. },
+    } = sub_response_rx.recv().await.unwrap()
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(id, Some(6));
+    assert_eq!(events.len(), 1000);
+    assert_eq!(events[0].block_number, 201);
+    assert_eq!(events[999].block_number, 1200);
+
+    let Response {
+        msg: ResponseMessage::Events { events, .. },
+        ..
+    } = sub_response_rx.recv().await.unwrap()
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 299);
+    assert_eq!(events[0].block_number, 1201);
+    assert_eq!(events[298].block_number, 1499);
+
+    indexer.index_event(key.clone(), 1500, 5).unwrap();
+
+    let Response {
+        msg: ResponseMessage::Events { events, .. },
+        ..
+    } = sub_response_rx.recv().await.unwrap()
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].block_number, 1500);
+
+    let response_msg = sub_response_rx.try_recv();
+
+    let Err(TryRecvError::Empty) = response_msg else {
+        panic!("Wrong response message.");
+    };
+}
+
+#[tokio::test]
+async fn test_process_msg_subscribe_events_since_limit() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
+    let key = Key::Substrate(SubstrateKey::AccountId(Bytes32([8; 32])));
+    for block_number in 0..u32::try_from(MAX_REPLAY_EVENTS).unwrap() + 2 {
+        indexer.index_event(key.clone(), block_number, 5).unwrap();
+    }
+
+    process_sub_msg(
+        &indexer,
+        SubscriptionMessage::SubscribeEvents {
+            key: key.clone(),
+            since: Some(Event {
+                block_number: 0,
+                event_index: 5,
+            }),
+            id: Some(6),
+            sub_response_tx: sub_response_tx.clone(),
+        },
+    );
+
+    // Too many events to replay, so only the error is sent and the key is not subscribed.
+    let Response {
+        id,
+        msg: ResponseMessage::Error { code, .. },
+    } = sub_response_rx.recv().await.unwrap()
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(id, Some(6));
+    assert_eq!(code, ErrorCode::LimitExceeded);
+    indexer.index_event(key.clone(), 20_000, 5).unwrap();
+    let Err(TryRecvError::Empty) = sub_response_rx.try_recv() else {
+        panic!("Wrong response message.");
+    };
+
+    // Replaying from a later event is within the limit.
+    process_sub_msg(
+        &indexer,
+        SubscriptionMessage::SubscribeEvents {
+            key: key.clone(),
+            since: Some(Event {
+                block_number: 2,
+                event_index: 5,
+            }),
+            id: Some(7),
+            sub_response_tx,
+        },
+    );
+    let Ok(Response {
+        id,
+        msg: ResponseMessage::Subscribed,
+    }) = sub_response_rx.try_recv()
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(id, Some(7));
+    let mut count = 0;
+    while let Ok(Response {
+        msg: ResponseMessage::Events { events, .. },
+        ..
+    }) = sub_response_rx.try_recv()
+    {
+        count += events.len();
+    }
+    assert_eq!(count, MAX_REPLAY_EVENTS);
+}
+
+#[test]
+fn test_load_spans() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    trees.span.clear().unwrap();
+    let spans = load_spans::<TestIndexer>(&trees.span, false).unwrap();
+    assert_eq!(trees.span.len().unwrap(), 0);
+    assert_eq!(spans.len(), 0);
+    let value = SpanDbValue {
+        start: 80_u32.into(),
+        version: 0_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(100_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+    let spans = load_spans::<TestIndexer>(&trees.span, false).unwrap();
+    assert_eq!(trees.span.len().unwrap(), 1);
+    assert_eq!(spans.len(), 1);
+    assert_eq!(
+        spans[0],
+        Span {
+            start: 80,
+            end: 100
+        }
+    );
+    let value = SpanDbValue {
+        start: 180_u32.into(),
+        version: 0_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(200_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+    let spans = load_spans::<TestIndexer>(&trees.span, false).unwrap();
+    assert_eq!(trees.span.len().unwrap(), 2);
+    assert_eq!(spans.len(), 2);
+    assert_eq!(
+        spans[0],
+        Span {
+            start: 80,
+            end: 100
+        }
+    );
+    assert_eq!(
         spans[1],
         Span {
             start: 180,
             end: 200
         }
     );
+    let spans = load_spans::<TestIndexer2>(&trees.span, false).unwrap();
+    assert_eq!(trees.span.len().unwrap(), 2);
+    assert_eq!(spans.len(), 2);
+    assert_eq!(
+        spans[0],
+        Span {
+            start: 80,
+            end: 100
+        }
+    );
+    assert_eq!(
+        spans[1],
+        Span {
+            start: 180,
+            end: 200
+        }
+    );
+    let value = SpanDbValue {
+        start: 400_u32.into(),
+        version: 0_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(600_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+    let spans = load_spans::<TestIndexer2>(&trees.span, false).unwrap();
+    assert_eq!(trees.span.len().unwrap(), 3);
+    assert_eq!(spans.len(), 3);
+    assert_eq!(
+        spans[0],
+        Span {
+            start: 80,
+            end: 100
+        }
+    );
+    assert_eq!(
+        spans[1],
+        Span {
+            start: 180,
+            end: 200
+        }
+    );
+    assert_eq!(
+        spans[2],
+        Span {
+            start: 400,
+            end: 499
+        }
+    );
+    let value = SpanDbValue {
+        start: 500_u32.into(),
+        version: 0_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(600_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+    let spans = load_spans::<TestIndexer2>(&trees.span, false).unwrap();
+    assert_eq!(trees.span.len().unwrap(), 3);
+    assert_eq!(spans.len(), 3);
+    assert_eq!(
+        spans[0],
+        Span {
+            start: 80,
+            end: 100
+        }
+    );
+    assert_eq!(
+        spans[1],
+        Span {
+            start: 180,
+            end: 200
+        }
+    );
+    assert_eq!(
+        spans[2],
+        Span {
+            start: 400,
+            end: 499
+        }
+    );
+    let value = SpanDbValue {
+        start: 500_u32.into(),
+        version: 1_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(600_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+    let spans = load_spans::<TestIndexer2>(&trees.span, false).unwrap();
+    assert_eq!(trees.span.len().unwrap(), 4);
+    assert_eq!(spans.len(), 4);
+    assert_eq!(
+        spans[0],
+        Span {
+            start: 80,
+            end: 100
+        }
+    );
+    assert_eq!(
+        spans[1],
+        Span {
+            start: 180,
+            end: 200
+        }
+    );
+    assert_eq!(
+        spans[2],
+        Span {
+            start: 400,
+            end: 499
+        }
+    );
+    assert_eq!(
+        spans[3],
+        Span {
+            start: 500,
+            end: 600
+        }
+    );
+}
+
+#[test]
+fn test_check_span() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    trees.span.clear().unwrap();
+    let mut spans = Vec::new();
+    let mut span = Span {
+        start: 100,
+        end: 120,
+    };
+    let mut batch = DbBatch::default();
+    check_span(&trees.span, &mut batch, &mut spans, &mut span);
+    trees.root.apply_batch(batch).unwrap();
+    assert_eq!(trees.span.len().unwrap(), 0);
+    assert_eq!(spans.len(), 0);
+    assert_eq!(
+        span,
+        Span {
+            start: 100,
+            end: 120
+        }
+    );
+    let value = SpanDbValue {
+        start: 10_u32.into(),
+        version: 0_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(20_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+    spans.push(Span { start: 10, end: 20 });
+    let mut batch = DbBatch::default();
+    check_span(&trees.span, &mut batch, &mut spans, &mut span);
+    trees.root.apply_batch(batch).unwrap();
+    assert_eq!(trees.span.len().unwrap(), 1);
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0], Span { start: 10, end: 20 });
+    assert_eq!(
+        span,
+        Span {
+            start: 100,
+            end: 120
+        }
+    );
+    let value = SpanDbValue {
+        start: 30_u32.into(),
+        version: 0_u16.into(),
+        index_variant: 0,
+    };
+    trees
+        .span
+        .insert(99_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
+    spans.push(Span { start: 30, end: 99 });
+    let mut batch = DbBatch::default();
+    check_span(&trees.span, &mut batch, &mut spans, &mut span);
+    trees.root.apply_batch(batch).unwrap();
+    assert_eq!(trees.span.len().unwrap(), 1);
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0], Span { start: 10, end: 20 });
+    assert_eq!(
+        span,
+        Span {
+            start: 30,
+            end: 120
+        }
+    );
+}
+
+#[test]
+fn test_commit_block() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
+    let key = Key::Substrate(SubstrateKey::AccountId(Bytes32([8; 32])));
+    process_sub_msg(
+        &indexer,
+        SubscriptionMessage::SubscribeEvents {
+            key: key.clone(),
+            since: None,
+            id: None,
+            sub_response_tx,
+        },
+    );
+    let Ok(Response {
+        msg: ResponseMessage::Subscribed,
+        ..
+    }) = sub_response_rx.try_recv()
+    else {
+        panic!("Wrong response message.");
+    };
+    let event = Event {
+        block_number: 12,
+        event_index: 3,
+    };
+    let mut block_batch = BlockBatch {
+        batch: DbBatch::default(),
+        events: Vec::new(),
+    };
+    key.write_db_key(&trees, &mut block_batch.batch, 12, 3);
+    block_batch.events.push((key.clone(), event.clone()));
+    let span = Span { start: 10, end: 12 };
+    write_span::<TestIndexer>(&trees.span, &mut block_batch.batch, &span, true);
+    // Nothing is written or sent before the block is committed.
+    assert_eq!(trees.substrate.account_id.len().unwrap(), 0);
+    assert_eq!(trees.span.len().unwrap(), 0);
+    assert!(matches!(
+        sub_response_rx.try_recv(),
+        Err(TryRecvError::Empty)
+    ));
+
+    indexer.commit_block(block_batch).unwrap();
+    assert_eq!(trees.substrate.account_id.len().unwrap(), 1);
+    assert_eq!(
+        load_spans::<TestIndexer>(&trees.span, true).unwrap(),
+        vec![span]
+    );
+    let Response {
+        msg: ResponseMessage::Events { events, .. },
+        ..
+    } = sub_response_rx.try_recv().unwrap()
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(events, vec![event]);
+}
+
+#[test]
+fn test_add_orphan() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let mut orphans = BTreeMap::new();
+    for block_number in [10, 12, 20, 11, 9, 19] {
+        let mut batch = DbBatch::default();
+        add_orphan::<TestIndexer>(&trees.span, &mut batch, &mut orphans, block_number, true);
+        trees.root.apply_batch(batch).unwrap();
+    }
+    assert_eq!(orphans, BTreeMap::from([(12, 9), (20, 19)]));
+    let spans = load_spans::<TestIndexer>(&trees.span, true).unwrap();
+    assert_eq!(
+        spans,
+        vec![Span { start: 9, end: 12 }, Span { start: 19, end: 20 }]
+    );
+}
+
+#[test]
+fn test_check_next_batch_block() {
+    let mut spans = Vec::new();
+    let mut next_batch_block = Some(50);
+
+    check_next_batch_block(&spans, &mut next_batch_block);
+    assert_eq!(next_batch_block, Some(50));
+    spans.push(Span { start: 20, end: 30 });
+    check_next_batch_block(&spans, &mut next_batch_block);
+    assert_eq!(next_batch_block, Some(50));
+    spans.push(Span { start: 45, end: 50 });
+    check_next_batch_block(&spans, &mut next_batch_block);
+    assert_eq!(next_batch_block, Some(44));
+    // A span reaching genesis ends the backfill.
+    let mut next_batch_block = Some(5);
+    check_next_batch_block(&[Span { start: 0, end: 10 }], &mut next_batch_block);
+    assert_eq!(next_batch_block, None);
+}
+
+#[test]
+fn test_take_next_batch_block() {
+    let spans = vec![Span { start: 3, end: 4 }];
+    let mut next_batch_block = Some(6);
+    let mut in_flight = BTreeSet::new();
+    let mut blocks = Vec::new();
+    while let Some(block_number) =
+        take_next_batch_block(&spans, &mut next_batch_block, 0, &mut in_flight, None)
+    {
+        blocks.push(block_number);
+    }
+    // Stops at genesis without underflowing.
+    assert_eq!(blocks, vec![6, 5, 2, 1, 0]);
+    assert_eq!(next_batch_block, None);
+    assert_eq!(
+        take_next_batch_block(&spans, &mut next_batch_block, 0, &mut in_flight, None),
+        None
+    );
+    assert_eq!(in_flight, BTreeSet::from([0, 1, 2, 5, 6]));
+    // Stops at the lower bound.
+    let mut next_batch_block = Some(6);
+    let mut in_flight = BTreeSet::new();
+    assert_eq!(
+        take_next_batch_block(&spans, &mut next_batch_block, 5, &mut in_flight, None),
+        Some(6)
+    );
+    assert_eq!(
+        take_next_batch_block(&spans, &mut next_batch_block, 5, &mut in_flight, None),
+        Some(5)
+    );
+    assert_eq!(
+        take_next_batch_block(&spans, &mut next_batch_block, 5, &mut in_flight, None),
+        None
+    );
+    assert_eq!(next_batch_block, Some(2));
+    // Blocks still in flight are not queued again.
+    let mut next_batch_block = Some(8);
+    let mut in_flight = BTreeSet::from([6, 7]);
+    assert_eq!(
+        take_next_batch_block(&spans, &mut next_batch_block, 0, &mut in_flight, None),
+        Some(8)
+    );
     assert_eq!(
-        spans[2],
-        Span {
-            start: 400,
-            end: 499
-        }
+        take_next_batch_block(&spans, &mut next_batch_block, 0, &mut in_flight, None),
+        Some(5)
     );
-    let value = SpanDbValue {
-        start: 500_u32.into(),
-        version: 1_u16.into(),
-        index_variant: 0,
+    assert_eq!(in_flight, BTreeSet::from([5, 6, 7, 8]));
+    // Blocks whose keys are being deleted are skipped.
+    let mut next_batch_block = Some(10);
+    let mut in_flight = BTreeSet::new();
+    let deleting = Span { start: 6, end: 9 };
+    assert_eq!(
+        take_next_batch_block(
+            &spans,
+            &mut next_batch_block,
+            0,
+            &mut in_flight,
+            Some(&deleting)
+        ),
+        Some(10)
+    );
+    assert_eq!(
+        take_next_batch_block(
+            &spans,
+            &mut next_batch_block,
+            0,
+            &mut in_flight,
+            Some(&deleting)
+        ),
+        Some(5)
+    );
+}
+
+fn check_storage_backend(db: Db) {
+    let tree = db.open_tree(b"test").unwrap();
+    assert!(tree.is_empty().unwrap());
+    for i in 0..10_u32 {
+        tree.insert(i.to_be_bytes(), [i as u8]).unwrap();
+    }
+    assert_eq!(tree.len().unwrap(), 10);
+    assert_eq!(tree.get(3_u32.to_be_bytes()).unwrap(), Some(vec![3]));
+    assert_eq!(tree.get(10_u32.to_be_bytes()).unwrap(), None);
+    // Range scans in both directions.
+    let keys: Vec<u32> = tree
+        .range(2_u32.to_be_bytes()..5_u32.to_be_bytes())
+        .map(|item| u32::from_be_bytes(item.unwrap().0.try_into().unwrap()))
+        .collect();
+    assert_eq!(keys, vec![2, 3, 4]);
+    let keys: Vec<u32> = tree
+        .range(2_u32.to_be_bytes()..=5_u32.to_be_bytes())
+        .rev()
+        .map(|item| u32::from_be_bytes(item.unwrap().0.try_into().unwrap()))
+        .collect();
+    assert_eq!(keys, vec![5, 4, 3, 2]);
+    assert_eq!(
+        tree.range(5_u32.to_be_bytes()..2_u32.to_be_bytes()).count(),
+        0
+    );
+    assert_eq!(tree.scan_prefix([0, 0, 0]).count(), 10);
+    assert_eq!(tree.scan_prefix([0, 0, 1]).count(), 0);
+    // Batches.
+    let mut batch = Batch::default();
+    batch.remove(0_u32.to_be_bytes());
+    batch.insert(20_u32.to_be_bytes(), []);
+    tree.apply_batch(batch).unwrap();
+    assert_eq!(tree.get(0_u32.to_be_bytes()).unwrap(), None);
+    assert_eq!(tree.get(20_u32.to_be_bytes()).unwrap(), Some(vec![]));
+    tree.remove(20_u32.to_be_bytes()).unwrap();
+    assert_eq!(tree.len().unwrap(), 9);
+    // Trees are independent of each other and of the root tree.
+    let other = db.open_tree(b"other").unwrap();
+    assert!(other.is_empty().unwrap());
+    db.insert("genesis_hash", [1, 2]).unwrap();
+    assert_eq!(db.get("genesis_hash").unwrap(), Some(vec![1, 2]));
+    assert_eq!(db.open_tree(b"test").unwrap().len().unwrap(), 9);
+    let mut names = db.tree_names().unwrap();
+    names.sort();
+    assert_eq!(names, vec![b"other".to_vec(), b"test".to_vec()]);
+    // Batches across trees.
+    let mut batch = DbBatch::default();
+    batch.insert(&other, [1], [1]);
+    batch.remove(&tree, 1_u32.to_be_bytes());
+    batch.insert(&other, [2], [2]);
+    db.apply_batch(batch).unwrap();
+    assert_eq!(other.len().unwrap(), 2);
+    assert_eq!(tree.get(1_u32.to_be_bytes()).unwrap(), None);
+    assert_eq!(tree.len().unwrap(), 8);
+    tree.clear().unwrap();
+    assert!(tree.is_empty().unwrap());
+    db.flush().unwrap();
+}
+
+#[test]
+fn test_storage_backends() {
+    check_storage_backend(Db::memory());
+    check_storage_backend(Db::sled(sled::Config::new().temporary(true)).unwrap());
+    #[cfg(feature = "redb")]
+    {
+        let path =
+            std::env::temp_dir().join(format!("acuity-index-test-{}.redb", std::process::id()));
+        check_storage_backend(Db::redb(&path, 1_000_000).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+fn migrate_test(db: &Db) -> Result<(), IndexError> {
+    db.insert("migrated", [1])?;
+    Ok(())
+}
+
+#[test]
+fn test_migrate_schema() {
+    // New databases are recorded at the current version.
+    let db = Db::memory();
+    schema::check_schema(&db).unwrap();
+    assert_eq!(
+        schema::read_schema_version(&db).unwrap(),
+        Some(schema::SCHEMA_VERSION)
+    );
+    // Databases from before versioning are at the legacy version.
+    let db = Db::memory();
+    db.insert("genesis_hash", [0; 32]).unwrap();
+    let migrations = [schema::Migration {
+        from: 1,
+        description: "test",
+        migrate: migrate_test,
+    }];
+    schema::migrate_schema(&db, 2, &migrations).unwrap();
+    assert_eq!(schema::read_schema_version(&db).unwrap(), Some(2));
+    assert_eq!(db.get("migrated").unwrap(), Some(vec![1]));
+    // Missing migrations are refused.
+    let Err(IndexError::MigrationNotFound { version: 2 }) =
+        schema::migrate_schema(&db, 3, &migrations)
+    else {
+        panic!("Migration should not be found.");
     };
-    trees
-        .span
-        .insert(600_u32.to_be_bytes(), value.as_bytes())
+    assert_eq!(schema::read_schema_version(&db).unwrap(), Some(2));
+    // Newer databases are refused.
+    let Err(IndexError::SchemaTooNew {
+        version: 2,
+        supported: 1,
+    }) = schema::migrate_schema(&db, 1, &migrations)
+    else {
+        panic!("Schema should be too new.");
+    };
+}
+
+#[test]
+fn test_init_database() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    init_database::<TestIndexer>(&trees.root).unwrap();
+    assert_eq!(
+        schema::read_schema_version(&trees.root).unwrap(),
+        Some(schema::SCHEMA_VERSION)
+    );
+    assert_eq!(
+        trees.root.get("genesis_hash").unwrap().unwrap(),
+        TestIndexer::get_genesis_hash().as_ref()
+    );
+    init_database::<TestIndexer>(&trees.root).unwrap();
+    // A new database is recorded at the current version, even once it is newer than the legacy version.
+    let migrations = [schema::Migration {
+        from: 1,
+        description: "test",
+        migrate: migrate_test,
+    }];
+    let db = Db::memory();
+    init_database_version(&db, &[1; 32], 2, &migrations).unwrap();
+    assert_eq!(schema::read_schema_version(&db).unwrap(), Some(2));
+    assert_eq!(db.get("migrated").unwrap(), None);
+    // Databases for other chains are refused before they are touched.
+    let db = Db::memory();
+    db.insert("genesis_hash", [0; 32]).unwrap();
+    let Err(IndexError::WrongGenesisHash(genesis_hash)) =
+        init_database_version(&db, &[1; 32], 2, &migrations)
+    else {
+        panic!("Genesis hash should be wrong.");
+    };
+    assert_eq!(genesis_hash, vec![0; 32]);
+    assert_eq!(schema::read_schema_version(&db).unwrap(), None);
+}
+
+#[test]
+fn test_snapshot() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    init_database::<TestIndexer>(&trees.root).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    for block_number in 0..25_000 {
+        let key = Key::Substrate(SubstrateKey::AccountId(Bytes32([8; 32])));
+        indexer.index_event(key, block_number, 5).unwrap();
+    }
+    indexer
+        .index_event(Key::Chain(ChainKey::TestIndex(4)), 12, 1)
         .unwrap();
-    let spans = load_spans::<TestIndexer2>(&trees.span, false).unwrap();
-    assert_eq!(trees.span.len(), 4);
-    assert_eq!(spans.len(), 4);
+    indexer.index_event(Key::Variant(3, 4), 12, 1).unwrap();
+    let mut batch = DbBatch::default();
+    write_span::<TestIndexer>(
+        &trees.span,
+        &mut batch,
+        &Span {
+            start: 0,
+            end: 24_999,
+        },
+        true,
+    );
+    trees.root.apply_batch(batch).unwrap();
+
+    let mut snapshot = Vec::new();
+    assert_eq!(export_snapshot(&trees, &mut snapshot).unwrap(), 25_005);
+
+    let imported = open_trees::<TestIndexer>(Db::memory()).unwrap();
     assert_eq!(
-        spans[0],
-        Span {
-            start: 80,
-            end: 100
+        import_snapshot::<TestIndexer>(&imported, snapshot.as_slice()).unwrap(),
+        25_005
+    );
+    assert_eq!(imported.substrate.account_id.len().unwrap(), 25_000);
+    assert_eq!(imported.chain.test_index.len().unwrap(), 1);
+    assert_eq!(imported.variant.len().unwrap(), 1);
+    assert_eq!(
+        load_spans::<TestIndexer>(&imported.span, true).unwrap(),
+        vec![Span {
+            start: 0,
+            end: 24_999
+        }]
+    );
+    assert_eq!(
+        imported.root.get("genesis_hash").unwrap(),
+        trees.root.get("genesis_hash").unwrap()
+    );
+    // Only fresh databases can be imported into.
+    let Err(IndexError::InvalidSnapshot("database is not empty")) =
+        import_snapshot::<TestIndexer>(&imported, snapshot.as_slice())
+    else {
+        panic!("Import should fail.");
+    };
+    // Corrupt snapshots are rejected and nothing is left in the database.
+    let mut corrupt = snapshot.clone();
+    let len = corrupt.len();
+    corrupt[len - 100] ^= 1;
+    let imported = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let Err(IndexError::InvalidSnapshot("hash mismatch")) =
+        import_snapshot::<TestIndexer>(&imported, corrupt.as_slice())
+    else {
+        panic!("Import should fail.");
+    };
+    assert!(imported.root.is_empty().unwrap());
+    assert!(imported.substrate.account_id.is_empty().unwrap());
+    // Oversized entries are rejected before they are read.
+    let mut corrupt = snapshot.clone();
+    corrupt[14..18].copy_from_slice(&u32::MAX.to_be_bytes());
+    let Err(IndexError::InvalidSnapshot("entry too large")) =
+        import_snapshot::<TestIndexer>(&imported, corrupt.as_slice())
+    else {
+        panic!("Import should fail.");
+    };
+    // Snapshots are only imported for the right chain.
+    trees.root.insert("genesis_hash", [0; 32]).unwrap();
+    let mut snapshot = Vec::new();
+    export_snapshot(&trees, &mut snapshot).unwrap();
+    let Err(IndexError::InvalidSnapshot("wrong genesis hash")) =
+        import_snapshot::<TestIndexer>(&imported, snapshot.as_slice())
+    else {
+        panic!("Import should fail.");
+    };
+}
+
+fn open_merge_test_trees(
+    spans: &[(u32, u32, u16, u8)],
+) -> Trees<<ChainKey as IndexKey>::ChainTrees> {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    init_database::<TestIndexer>(&trees.root).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    for (start, end, version, index_variant) in spans {
+        for block_number in *start..=*end {
+            let key = Key::Substrate(SubstrateKey::AccountId(Bytes32([8; 32])));
+            indexer.index_event(key, block_number, 0).unwrap();
+            indexer
+                .index_event(Key::Chain(ChainKey::TestIndex(1)), block_number, 1)
+                .unwrap();
         }
+        let value = SpanDbValue {
+            start: (*start).into(),
+            version: (*version).into(),
+            index_variant: *index_variant,
+        };
+        trees
+            .span
+            .insert(end.to_be_bytes(), value.as_bytes())
+            .unwrap();
+    }
+    trees
+}
+
+#[test]
+fn test_coalesce_spans() {
+    let record = |start, end, index_variant| SpanRecord {
+        span: Span { start, end },
+        version: 0,
+        index_variant,
+    };
+    assert_eq!(
+        coalesce_spans(vec![
+            record(50, 60, true),
+            record(0, 10, true),
+            record(11, 20, false),
+            record(15, 30, true),
+            record(40, 45, true),
+        ]),
+        vec![
+            record(0, 10, true),
+            record(11, 14, false),
+            record(15, 30, true),
+            record(40, 45, true),
+            record(50, 60, true)
+        ]
     );
+    // Spans with the same flags are coalesced, and spans without event variants inside spans with them are dropped.
     assert_eq!(
-        spans[1],
+        coalesce_spans(vec![
+            record(0, 10, false),
+            record(5, 20, false),
+            record(2, 8, true),
+            record(21, 30, false),
+            record(25, 30, true),
+        ]),
+        vec![
+            record(0, 1, false),
+            record(2, 8, true),
+            record(9, 24, false),
+            record(25, 30, true),
+        ]
+    );
+}
+
+#[test]
+fn test_merge_databases() {
+    let trees = open_merge_test_trees(&[(0, 99, 0, 1), (300, 400, 0, 1)]);
+    let source = open_merge_test_trees(&[(100, 199, 0, 1), (350, 450, 0, 0)]);
+    trees.failed.insert(150_u32.to_be_bytes(), []).unwrap();
+    source.failed.insert(250_u32.to_be_bytes(), []).unwrap();
+    assert_eq!(merge_databases(&trees, &source).unwrap(), 403);
+    assert_eq!(
+        read_span_records(&trees.span).unwrap(),
+        vec![
+            SpanRecord {
+                span: Span { start: 0, end: 199 },
+                version: 0,
+                index_variant: true,
+            },
+            SpanRecord {
+                span: Span {
+                    start: 300,
+                    end: 400
+                },
+                version: 0,
+                index_variant: true,
+            },
+            SpanRecord {
+                span: Span {
+                    start: 401,
+                    end: 450
+                },
+                version: 0,
+                index_variant: false,
+            },
+        ]
+    );
+    // Failed blocks are merged unless the other database has indexed them.
+    assert_eq!(
+        read_failed_blocks(&trees.failed, MAX_FAILED_BLOCKS).unwrap(),
+        vec![250]
+    );
+    assert_eq!(trees.substrate.account_id.len().unwrap(), 351);
+    assert_eq!(trees.chain.test_index.len().unwrap(), 351);
+    // Spans indexed by different indexer versions are rejected.
+    let source = open_merge_test_trees(&[(500, 600, 1, 1)]);
+    let Err(IndexError::MergeConflict("indexer version")) = merge_databases(&trees, &source) else {
+        panic!("Merge should fail.");
+    };
+    // Databases for different chains are rejected.
+    let source = open_merge_test_trees(&[(500, 600, 0, 1)]);
+    source.root.insert("genesis_hash", [0; 32]).unwrap();
+    let Err(IndexError::MergeConflict("genesis hash")) = merge_databases(&trees, &source) else {
+        panic!("Merge should fail.");
+    };
+    // Databases with unfinalized best blocks are rejected.
+    let source = open_merge_test_trees(&[(500, 600, 0, 1)]);
+    source
+        .root
+        .insert(
+            "best_span",
+            serde_json::to_vec(&Span {
+                start: 601,
+                end: 603,
+            })
+            .unwrap(),
+        )
+        .unwrap();
+    let Err(IndexError::MergeConflict("unfinalized best blocks")) =
+        merge_databases(&trees, &source)
+    else {
+        panic!("Merge should fail.");
+    };
+    assert_eq!(trees.substrate.account_id.len().unwrap(), 351);
+}
+
+#[test]
+fn test_retention_min_block() {
+    assert_eq!(prune::retention_min_block(999, 1000), 0);
+    assert_eq!(prune::retention_min_block(1999, 1000), 1000);
+    assert_eq!(prune::retention_min_block(10, 1000), 0);
+}
+
+#[test]
+fn test_prune_trees() {
+    let trees = open_merge_test_trees(&[(0, 99, 0, 1), (120, 199, 0, 1), (300, 349, 0, 0)]);
+    for block_number in [110_u32, 149, 250] {
+        trees.failed.insert(block_number.to_be_bytes(), []).unwrap();
+    }
+    assert_eq!(prune::prune_trees(&trees, 150).unwrap(), 260);
+    // Failed blocks before the window are forgotten.
+    assert_eq!(
+        read_failed_blocks(&trees.failed, MAX_FAILED_BLOCKS).unwrap(),
+        vec![250]
+    );
+    assert_eq!(
+        read_span_records(&trees.span).unwrap(),
+        vec![
+            SpanRecord {
+                span: Span {
+                    start: 150,
+                    end: 199
+                },
+                version: 0,
+                index_variant: true,
+            },
+            SpanRecord {
+                span: Span {
+                    start: 300,
+                    end: 349
+                },
+                version: 0,
+                index_variant: false,
+            },
+        ]
+    );
+    assert_eq!(trees.substrate.account_id.len().unwrap(), 100);
+    assert_eq!(trees.chain.test_index.len().unwrap(), 100);
+    let key = Bytes32Key {
+        key: [8; 32],
+        block_number: 150.into(),
+        event_index: 0.into(),
+    };
+    assert!(trees
+        .substrate
+        .account_id
+        .get(key.as_bytes())
+        .unwrap()
+        .is_some());
+    // Pruning again does nothing.
+    assert_eq!(prune::prune_trees(&trees, 150).unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_spawn_prune_trees() {
+    let trees = open_merge_test_trees(&[(0, 99, 0, 1), (120, 199, 0, 1), (300, 349, 0, 0)]);
+    assert!(prune::spawn_prune_trees(&trees, 0).unwrap().is_none());
+    let task = prune::spawn_prune_trees(&trees, 150).unwrap().unwrap();
+    // The spans are truncated before the keys are swept.
+    assert_eq!(read_span_records(&trees.span).unwrap()[0].span.start, 150);
+    task.await.unwrap();
+    assert_eq!(trees.substrate.account_id.len().unwrap(), 100);
+    assert_eq!(trees.chain.test_index.len().unwrap(), 100);
+}
+
+#[test]
+fn test_batch_writer() {
+    let db = Db::memory();
+    let tree = db.open_tree(b"test").unwrap();
+    let mut writer = BatchWriter::new(tree.clone());
+    for i in 0..WRITE_BATCH_SIZE + 1 {
+        writer
+            .insert(u32::try_from(i).unwrap().to_be_bytes(), [])
+            .unwrap();
+    }
+    // Full batches are applied as they are written.
+    assert_eq!(tree.len().unwrap(), WRITE_BATCH_SIZE);
+    writer.remove(0_u32.to_be_bytes()).unwrap();
+    writer.finish().unwrap();
+    assert_eq!(tree.len().unwrap(), WRITE_BATCH_SIZE);
+    assert_eq!(tree.get(0_u32.to_be_bytes()).unwrap(), None);
+}
+
+#[test]
+fn test_prune_spans() {
+    let mut spans = vec![
+        Span { start: 0, end: 99 },
         Span {
-            start: 180,
-            end: 200
-        }
+            start: 120,
+            end: 199,
+        },
+    ];
+    let mut current_span = Span {
+        start: 300,
+        end: 400,
+    };
+    let mut orphans = BTreeMap::from([(250, 240), (280, 260)]);
+    prune::prune_spans(&mut spans, &mut current_span, &mut orphans, 150);
+    assert_eq!(
+        spans,
+        vec![Span {
+            start: 150,
+            end: 199
+        }]
     );
     assert_eq!(
-        spans[2],
+        current_span,
         Span {
-            start: 400,
-            end: 499
+            start: 300,
+            end: 400
         }
     );
+    assert_eq!(orphans, BTreeMap::from([(250, 240), (280, 260)]));
+    prune::prune_spans(&mut spans, &mut current_span, &mut orphans, 350);
+    assert_eq!(spans, vec![]);
     assert_eq!(
-        spans[3],
+        current_span,
         Span {
-            start: 500,
-            end: 600
+            start: 350,
+            end: 400
         }
     );
+    assert_eq!(orphans, BTreeMap::new());
 }
 
 #[test]
-fn test_check_span() {
-    let db_config = sled::Config::new().temporary(true);
-    let trees = open_trees::<TestIndexer>(db_config).unwrap();
-    trees.span.clear().unwrap();
-    let mut spans = Vec::new();
-    let mut span = Span {
-        start: 100,
-        end: 120,
+fn test_check_database() {
+    let trees = open_merge_test_trees(&[(0, 99, 0, 1), (200, 299, 0, 1)]);
+    let report = check_database(&trees).unwrap();
+    assert!(report.is_consistent());
+    assert_eq!(report.span_count, 2);
+    assert_eq!(report.key_count, 400);
+    // Keys outside all spans.
+    trees.span.remove(99_u32.to_be_bytes()).unwrap();
+    // Adjacent and overlapping spans.
+    let value = SpanDbValue {
+        start: 300.into(),
+        version: 0.into(),
+        index_variant: 1,
     };
-    check_span(&trees.span, &mut spans, &mut span).unwrap();
-    assert_eq!(trees.span.len(), 0);
-    assert_eq!(spans.len(), 0);
-    assert_eq!(
-        span,
-        Span {
-            start: 100,
-            end: 120
-        }
-    );
+    trees
+        .span
+        .insert(310_u32.to_be_bytes(), value.as_bytes())
+        .unwrap();
     let value = SpanDbValue {
-        start: 10_u32.into(),
-        version: 0_u16.into(),
-        index_variant: 0,
+        start: 305.into(),
+        version: 0.into(),
+        index_variant: 1,
     };
     trees
         .span
-        .insert(20_u32.to_be_bytes(), value.as_bytes())
+        .insert(320_u32.to_be_bytes(), value.as_bytes())
         .unwrap();
-    spans.push(Span { start: 10, end: 20 });
-    check_span(&trees.span, &mut spans, &mut span).unwrap();
-    assert_eq!(trees.span.len(), 1);
-    assert_eq!(spans.len(), 1);
-    assert_eq!(spans[0], Span { start: 10, end: 20 });
-    assert_eq!(
-        span,
-        Span {
-            start: 100,
-            end: 120
-        }
-    );
+    // Reversed and undecodable spans.
     let value = SpanDbValue {
-        start: 30_u32.into(),
-        version: 0_u16.into(),
-        index_variant: 0,
+        start: 500.into(),
+        version: 0.into(),
+        index_variant: 1,
     };
     trees
         .span
-        .insert(99_u32.to_be_bytes(), value.as_bytes())
+        .insert(400_u32.to_be_bytes(), value.as_bytes())
         .unwrap();
-    spans.push(Span { start: 30, end: 99 });
-    check_span(&trees.span, &mut spans, &mut span).unwrap();
-    assert_eq!(trees.span.len(), 1);
-    assert_eq!(spans.len(), 1);
-    assert_eq!(spans[0], Span { start: 10, end: 20 });
+    trees.span.insert([1, 2], [3]).unwrap();
+    // Key too short to have a block number.
+    trees.chain.test_index.insert([1, 2, 3], []).unwrap();
+    let report = check_database(&trees).unwrap();
+    assert!(!report.is_consistent());
+    assert_eq!(report.span_count, 3);
+    assert_eq!(report.key_count, 401);
     assert_eq!(
-        span,
+        report.issues,
+        vec![
+            FsckIssue::ReversedSpan {
+                start: 500,
+                end: 400,
+            },
+            FsckIssue::InvalidSpan {
+                key: "0102".to_owned(),
+            },
+            FsckIssue::AdjacentSpans {
+                first: Span {
+                    start: 200,
+                    end: 299
+                },
+                second: Span {
+                    start: 300,
+                    end: 310
+                },
+            },
+            FsckIssue::OverlappingSpans {
+                first: Span {
+                    start: 300,
+                    end: 310
+                },
+                second: Span {
+                    start: 305,
+                    end: 320
+                },
+            },
+            FsckIssue::KeysOutsideSpans {
+                tree: "account_id".to_owned(),
+                count: 100,
+                first_block: 0,
+                last_block: 99,
+            },
+            FsckIssue::InvalidKey {
+                tree: "test_index".to_owned(),
+                key: "010203".to_owned(),
+            },
+            FsckIssue::KeysOutsideSpans {
+                tree: "test_index".to_owned(),
+                count: 100,
+                first_block: 0,
+                last_block: 99,
+            },
+        ]
+    );
+    let json = serde_json::to_value(&report.issues[4]).unwrap();
+    assert_eq!(json["type"], "KeysOutsideSpans");
+    assert_eq!(json["firstBlock"], 0);
+}
+
+#[test]
+fn test_sample_blocks() {
+    let records: Vec<_> = [(0, 9), (20, 29)]
+        .into_iter()
+        .map(|(start, end)| SpanRecord {
+            span: Span { start, end },
+            version: 0,
+            index_variant: true,
+        })
+        .collect();
+    assert_eq!(sample_blocks(&records, 4), vec![0, 5, 20, 25]);
+    assert!(sample_blocks(&records, 0).is_empty());
+    assert_eq!(sample_blocks(&records, 100).len(), 20);
+    assert!(sample_blocks(&[], 4).is_empty());
+}
+
+#[test]
+fn test_carve_span() {
+    let span = Span { start: 10, end: 20 };
+    assert_eq!(
+        prune::carve_span(&span, &Span { start: 12, end: 15 }),
+        vec![Span { start: 10, end: 11 }, Span { start: 16, end: 20 }]
+    );
+    assert_eq!(
+        prune::carve_span(&span, &Span { start: 0, end: 15 }),
+        vec![Span { start: 16, end: 20 }]
+    );
+    assert_eq!(
+        prune::carve_span(&span, &Span { start: 15, end: 30 }),
+        vec![Span { start: 10, end: 14 }]
+    );
+    assert_eq!(
+        prune::carve_span(&span, &Span { start: 5, end: 25 }),
+        vec![]
+    );
+}
+
+#[test]
+fn test_reindex_range() {
+    let trees = open_merge_test_trees(&[(0, 99, 0, 1), (200, 299, 0, 0)]);
+    let range = Span {
+        start: 50,
+        end: 249,
+    };
+    prune::carve_span_records(&trees.span, &range).unwrap();
+    assert_eq!(prune::delete_keys(&trees.root, &range).unwrap(), 200);
+    assert_eq!(
+        read_span_records(&trees.span).unwrap(),
+        vec![
+            SpanRecord {
+                span: Span { start: 0, end: 49 },
+                version: 0,
+                index_variant: true,
+            },
+            SpanRecord {
+                span: Span {
+                    start: 250,
+                    end: 299
+                },
+                version: 0,
+                index_variant: false,
+            },
+        ]
+    );
+    assert_eq!(trees.substrate.account_id.len().unwrap(), 100);
+    assert!(check_database(&trees).unwrap().is_consistent());
+    // In-memory spans and orphans.
+    let mut spans = vec![
+        Span { start: 0, end: 99 },
         Span {
-            start: 30,
-            end: 120
-        }
+            start: 200,
+            end: 299,
+        },
+    ];
+    let mut orphans = BTreeMap::from([(320, 310), (340, 330)]);
+    prune::carve_spans(
+        &mut spans,
+        &mut orphans,
+        &Span {
+            start: 50,
+            end: 335,
+        },
     );
+    assert_eq!(spans, vec![Span { start: 0, end: 49 }]);
+    assert_eq!(orphans, BTreeMap::from([(340, 336)]));
 }
 
 #[test]
-fn test_check_next_batch_block() {
-    let mut spans = Vec::new();
-    let mut next_batch_block = 50;
+fn test_is_block_indexed() {
+    let current_span = Span {
+        start: 100,
+        end: 200,
+    };
+    let orphans = BTreeMap::from([(50, 40), (60, 60)]);
+    assert!(is_block_indexed(&current_span, &orphans, 100));
+    assert!(is_block_indexed(&current_span, &orphans, 200));
+    assert!(is_block_indexed(&current_span, &orphans, 40));
+    assert!(is_block_indexed(&current_span, &orphans, 60));
+    assert!(!is_block_indexed(&current_span, &orphans, 39));
+    assert!(!is_block_indexed(&current_span, &orphans, 55));
+    assert!(!is_block_indexed(&current_span, &orphans, 201));
+}
 
-    check_next_batch_block(&spans, &mut next_batch_block);
-    assert_eq!(next_batch_block, 50);
-    spans.push(Span { start: 20, end: 30 });
-    check_next_batch_block(&spans, &mut next_batch_block);
-    assert_eq!(next_batch_block, 50);
-    spans.push(Span { start: 45, end: 50 });
-    check_next_batch_block(&spans, &mut next_batch_block);
-    assert_eq!(next_batch_block, 44);
+#[test]
+fn test_process_msg_reindex() {
+    let (admin_tx, mut admin_rx) = unbounded_channel();
+    let Ok(ResponseMessage::Reindexing(span)) =
+        process_msg_reindex::<TestIndexer>(10, 20, Some(&admin_tx))
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(span, Span { start: 10, end: 20 });
+    let AdminMessage::Reindex { span } = admin_rx.try_recv().unwrap();
+    assert_eq!(span, Span { start: 10, end: 20 });
+    let Err(IndexError::InvalidBlockRange) =
+        process_msg_reindex::<TestIndexer>(20, 10, Some(&admin_tx))
+    else {
+        panic!("Reindex should fail.");
+    };
+    let Err(IndexError::AdminDisabled) = process_msg_reindex::<TestIndexer>(10, 20, None) else {
+        panic!("Reindex should fail.");
+    };
+    assert!(admin_rx.try_recv().is_err());
+}
+
+#[test]
+fn test_best_blocks() {
+    let trees = open_trees::<TestIndexer>(Db::memory()).unwrap();
+    let indexer = Indexer::<TestIndexer>::new_test(trees.clone());
+    let (sub_response_tx, mut sub_response_rx) = unbounded_channel();
+    let key = Key::Substrate(SubstrateKey::AccountId(Bytes32([8; 32])));
+    process_sub_msg(
+        &indexer,
+        SubscriptionMessage::SubscribeEvents {
+            key: key.clone(),
+            since: None,
+            id: None,
+            sub_response_tx,
+        },
+    );
+    let Ok(Response {
+        msg: ResponseMessage::Subscribed,
+        ..
+    }) = sub_response_rx.try_recv()
+    else {
+        panic!("Wrong response message.");
+    };
+    for block_number in [10, 11] {
+        let mut block_batch = BlockBatch {
+            batch: DbBatch::default(),
+            events: Vec::new(),
+        };
+        key.write_db_key(&trees, &mut block_batch.batch, block_number, 0);
+        let event = Event {
+            block_number,
+            event_index: 0,
+        };
+        block_batch.events.push((key.clone(), event));
+        let hash = subxt::utils::H256::repeat_byte(block_number.try_into().unwrap());
+        indexer
+            .commit_best_block(block_number, hash, block_batch)
+            .unwrap();
+        assert!(indexer.is_best_block(block_number, &hash));
+        let Response {
+            msg: ResponseMessage::Events { .. },
+            ..
+        } = sub_response_rx.try_recv().unwrap()
+        else {
+            panic!("Wrong response message.");
+        };
+    }
+    assert!(!indexer.is_best_block(11, &subxt::utils::H256::zero()));
+    assert_eq!(trees.substrate.account_id.len().unwrap(), 2);
+    assert_eq!(
+        read_best_span(&trees.root).unwrap(),
+        Some(Span { start: 10, end: 11 })
+    );
+    // Retract block 11.
+    indexer.retract_best_blocks(11).unwrap();
+    assert_eq!(trees.substrate.account_id.len().unwrap(), 1);
+    assert_eq!(
+        read_best_span(&trees.root).unwrap(),
+        Some(Span { start: 10, end: 10 })
+    );
+    let Response {
+        msg: ResponseMessage::Retracted { events, .. },
+        ..
+    } = sub_response_rx.try_recv().unwrap()
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(
+        events,
+        vec![Event {
+            block_number: 11,
+            event_index: 0
+        }]
+    );
+    // Finalize block 10.
+    let mut batch = DbBatch::default();
+    let finalized = indexer.finalize_best_blocks(10, &mut batch);
+    trees.root.apply_batch(batch).unwrap();
+    assert_eq!(read_best_span(&trees.root).unwrap(), None);
+    assert_eq!(trees.substrate.account_id.len().unwrap(), 1);
+    indexer.notify_finalized(finalized);
+    let Response {
+        msg: ResponseMessage::Finalized { events, .. },
+        ..
+    } = sub_response_rx.try_recv().unwrap()
+    else {
+        panic!("Wrong response message.");
+    };
+    assert_eq!(
+        events,
+        vec![Event {
+            block_number: 10,
+            event_index: 0
+        }]
+    );
+    assert!(indexer.delete_best_blocks(0).unwrap().is_empty());
+}
+
+#[test]
+fn test_reconnect_policy() {
+    let mut reconnect = ReconnectPolicy::new(
+        vec![
+            "ws://a".to_string(),
+            "ws://b".to_string(),
+            "ws://c".to_string(),
+        ],
+        1,
+    );
+    assert_eq!(reconnect.url(), "ws://b");
+    assert_eq!(reconnect.delay(), Duration::from_secs(1));
+    // Each failed attempt tries the next endpoint after twice the delay.
+    reconnect.record_failure();
+    assert_eq!(reconnect.url(), "ws://c");
+    assert_eq!(reconnect.delay(), Duration::from_secs(2));
+    reconnect.record_failure();
+    assert_eq!(reconnect.url(), "ws://a");
+    assert_eq!(reconnect.delay(), Duration::from_secs(4));
+    for _ in 0..10 {
+        reconnect.record_failure();
+    }
+    assert_eq!(reconnect.url(), "ws://b");
+    assert_eq!(reconnect.delay(), Duration::from_secs(60));
+    // Connecting resets the delay and keeps the endpoint.
+    reconnect.record_success();
+    assert_eq!(reconnect.url(), "ws://b");
+    assert_eq!(reconnect.delay(), Duration::from_secs(1));
+}
+
+#[test]
+fn test_select_endpoint() {
+    let now = tokio::time::Instant::now();
+    let mut endpoints = [
+        EndpointHealth {
+            limit: Some(2),
+            in_flight: 1,
+            ..Default::default()
+        },
+        EndpointHealth::default(),
+    ];
+    // The least loaded endpoint is used.
+    assert_eq!(select_endpoint(endpoints.iter(), &[], now), Some(1));
+    endpoints[1].in_flight = 3;
+    assert_eq!(select_endpoint(endpoints.iter(), &[], now), Some(0));
+    // Endpoints at their limit are not used.
+    endpoints[0].in_flight = 2;
+    assert_eq!(select_endpoint(endpoints.iter(), &[], now), Some(1));
+    // Failed endpoints are not used until their delay has passed.
+    endpoints[1].record_failure(now);
+    assert_eq!(endpoints[1].failures, 1);
+    assert_eq!(select_endpoint(endpoints.iter(), &[], now), None);
+    assert_eq!(
+        select_endpoint(endpoints.iter(), &[], now + Duration::from_secs(1)),
+        Some(1)
+    );
+    endpoints[1].record_failure(now);
+    assert_eq!(endpoints[1].retry_at, Some(now + Duration::from_secs(2)));
+    endpoints[1].record_success();
+    assert_eq!(endpoints[1].failures, 0);
+    assert_eq!(select_endpoint(endpoints.iter(), &[], now), Some(1));
+    // Excluded endpoints are not used.
+    endpoints[0].in_flight = 1;
+    assert_eq!(select_endpoint(endpoints.iter(), &[1], now), Some(0));
+    assert_eq!(select_endpoint(endpoints.iter(), &[0, 1], now), None);
+    // Endpoints on a different chain are never used.
+    endpoints[1].disabled = true;
+    assert_eq!(select_endpoint(endpoints.iter(), &[], now), Some(0));
+}
+
+#[test]
+fn test_queue_depth_controller() {
+    let stats = |requests, errors, latency_ms| BatchStats {
+        requests,
+        errors,
+        latency: Duration::from_millis(latency_ms) * (requests - errors),
+    };
+    let mut controller = QueueDepthController::new(2, 20);
+    assert_eq!(controller.depth(), 2);
+    // Nothing to go on.
+    assert_eq!(controller.update(BatchStats::default(), 0), 2);
+    // Doubles until the first sign of overload.
+    assert_eq!(controller.update(stats(10, 0, 100), 5), 4);
+    assert_eq!(controller.update(stats(20, 0, 100), 10), 8);
+    assert_eq!(controller.update(stats(40, 0, 100), 20), 16);
+    // Halves when requests fail.
+    assert_eq!(controller.update(stats(40, 4, 100), 18), 8);
+    // Then grows by one block.
+    assert_eq!(controller.update(stats(40, 0, 100), 20), 9);
+    assert_eq!(controller.update(stats(40, 0, 100), 22), 10);
+    // Shrinks when a larger depth did not increase throughput.
+    assert_eq!(controller.update(stats(40, 0, 100), 15), 9);
+    // Shrinks when latency rises.
+    assert_eq!(controller.update(stats(40, 0, 300), 15), 8);
+    // Stays within the bounds.
+    for _ in 0..10 {
+        controller.update(stats(40, 40, 0), 0);
+    }
+    assert_eq!(controller.depth(), 2);
+    for _ in 0..40 {
+        controller.update(stats(40, 0, 100), 1000);
+    }
+    assert_eq!(controller.depth(), 20);
 }
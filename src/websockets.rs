@@ -1,34 +1,43 @@
-use crate::shared::*;
+use crate::{shared::*, storage::Tree};
 use futures::{SinkExt, StreamExt};
-use sled::Tree;
-use std::net::SocketAddr;
-use subxt::backend::legacy::LegacyRpcMethods;
+use std::{cmp::Ordering, collections::BTreeSet, iter, net::SocketAddr, ops::Bound, sync::Arc};
+use subxt::{backend::legacy::LegacyRpcMethods, metadata::Metadata};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{
     mpsc::{unbounded_channel, UnboundedSender},
     watch::Receiver,
+    RwLock,
 };
 use tokio_tungstenite::tungstenite;
 use tracing::{error, info};
-use zerocopy::FromBytes;
+use zerocopy::{AsBytes, FromBytes};
 
-pub fn process_msg_status<R: RuntimeIndexer>(span_db: &Tree) -> ResponseMessage<R::ChainKey> {
+pub fn process_msg_status<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+) -> ResponseMessage<R::ChainKey> {
     let mut spans = vec![];
-    for (key, value) in span_db.into_iter().flatten() {
+    for (key, value) in trees.span.iter().flatten() {
         let span_value = SpanDbValue::read_from(&value).unwrap();
         let start: u32 = span_value.start.into();
-        let end: u32 = u32::from_be_bytes(key.as_ref().try_into().unwrap());
+        let end: u32 = u32::from_be_bytes(key.as_slice().try_into().unwrap());
         let span = Span { start, end };
         spans.push(span);
     }
-    ResponseMessage::Status(spans)
+    ResponseMessage::Status {
+        spans,
+        target: IndexRange::read(&trees.root).ok().flatten(),
+        failed: read_failed_blocks(&trees.failed, MAX_FAILED_BLOCKS).unwrap_or_default(),
+        failed_count: trees.failed.len().unwrap_or_default().try_into().unwrap(),
+    }
 }
 
 pub fn process_msg_subscribe_status<R: RuntimeIndexer>(
+    id: Option<u32>,
     sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
-    sub_response_tx: &UnboundedSender<ResponseMessage<R::ChainKey>>,
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
 ) -> ResponseMessage<R::ChainKey> {
     let msg = SubscriptionMessage::SubscribeStatus {
+        id,
         sub_response_tx: sub_response_tx.clone(),
     };
     sub_tx.send(msg).unwrap();
@@ -37,7 +46,7 @@ pub fn process_msg_subscribe_status<R: RuntimeIndexer>(
 
 pub fn process_msg_unsubscribe_status<R: RuntimeIndexer>(
     sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
-    sub_response_tx: &UnboundedSender<ResponseMessage<R::ChainKey>>,
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
 ) -> ResponseMessage<R::ChainKey> {
     let msg = SubscriptionMessage::UnsubscribeStatus {
         sub_response_tx: sub_response_tx.clone(),
@@ -50,6 +59,11 @@ pub async fn process_msg_variants<R: RuntimeIndexer>(
     rpc: &LegacyRpcMethods<R::RuntimeConfig>,
 ) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
     let metadata = rpc.state_get_metadata(None).await?;
+    Ok(ResponseMessage::Variants(get_pallet_metas(&metadata)))
+}
+
+/// Gets the pallets that have events and their event variants from metadata.
+pub fn get_pallet_metas(metadata: &Metadata) -> Vec<PalletMeta> {
     let mut pallets = Vec::new();
 
     for pallet in metadata.pallets() {
@@ -69,178 +83,709 @@ pub async fn process_msg_variants<R: RuntimeIndexer>(
             pallets.push(pallet_meta);
         }
     }
-    Ok(ResponseMessage::Variants(pallets))
+    pallets
 }
 
-pub fn get_events_variant(tree: &Tree, pallet_id: u8, variant_id: u8) -> Vec<Event> {
-    let mut events = Vec::new();
-    let mut iter = tree.scan_prefix([pallet_id, variant_id]).keys();
-
-    while let Some(Ok(key)) = iter.next_back() {
-        let key = VariantKey::read_from(&key).unwrap();
+/// Number of events per page for a query.
+fn query_limit(query: &EventQuery) -> usize {
+    query
+        .limit
+        .unwrap_or(DEFAULT_EVENTS_LIMIT)
+        .clamp(1, MAX_EVENTS_LIMIT)
+        .into()
+}
 
-        events.push(Event {
-            block_number: key.block_number.into(),
-            event_index: key.event_index.into(),
-        });
+/// Takes a page of events from an iterator, returning the cursor for the next page if there are more events.
+fn take_page(mut iter: impl Iterator<Item = Event>, limit: usize) -> (Vec<Event>, Option<Event>) {
+    let mut events = Vec::new();
+    let mut next_cursor = None;
 
-        if events.len() == 100 {
+    for event in iter.by_ref() {
+        if events.len() == limit {
+            next_cursor = events.last().cloned();
             break;
         }
+        events.push(event);
     }
-    events
+    (events, next_cursor)
 }
 
-pub fn get_events_bytes32(tree: &Tree, key: &Bytes32) -> Vec<Event> {
-    let mut events = Vec::new();
-    let mut iter = tree.scan_prefix(key).keys();
-
-    while let Some(Ok(key)) = iter.next_back() {
-        let key = Bytes32Key::read_from(&key).unwrap();
-
-        events.push(Event {
-            block_number: key.block_number.into(),
-            event_index: key.event_index.into(),
-        });
-
-        if events.len() == 100 {
-            break;
+/// Iterates a key tree in the query order from the query cursor within the query block range, returning a page of events and the cursor for the next page.
+fn get_events_page(
+    tree: &Tree,
+    db_key: impl Fn(u32, u16) -> Vec<u8>,
+    read_event: impl Fn(&[u8]) -> Event,
+    query: &EventQuery,
+) -> (Vec<Event>, Option<Event>) {
+    let from_block = query.from_block.unwrap_or(0);
+    let to_block = query.to_block.unwrap_or(u32::MAX);
+    if from_block > to_block {
+        return (Vec::new(), None);
+    }
+    let mut start = Bound::Included(db_key(from_block, 0));
+    let mut end = Bound::Included(db_key(to_block, u16::MAX));
+    // Use whichever of the cursor and the block range bound comes first.
+    if let Some(cursor) = &query.cursor {
+        let cursor_key = Bound::Excluded(db_key(cursor.block_number, cursor.event_index));
+        match query.order {
+            Order::Descending => {
+                if cursor.block_number < from_block {
+                    return (Vec::new(), None);
+                }
+                if cursor.block_number <= to_block {
+                    end = cursor_key;
+                }
+            }
+            Order::Ascending => {
+                if cursor.block_number > to_block {
+                    return (Vec::new(), None);
+                }
+                if cursor.block_number >= from_block {
+                    start = cursor_key;
+                }
+            }
         }
     }
-    events
+    let keys = tree
+        .range((start, end))
+        .map(|item| item.map(|(key, _)| key));
+    let keys: Box<dyn Iterator<Item = _>> = match query.order {
+        Order::Descending => Box::new(keys.rev()),
+        Order::Ascending => Box::new(keys),
+    };
+    let iter = keys.map_while(Result::ok).map(|key| read_event(&key));
+    take_page(iter, query_limit(query))
 }
 
-pub fn get_events_u32(tree: &Tree, key: u32) -> Vec<Event> {
-    let mut events = Vec::new();
-    let mut iter = tree.scan_prefix(key.to_be_bytes()).keys();
-
-    while let Some(Ok(key)) = iter.next_back() {
-        let key = U32Key::read_from(&key).unwrap();
+pub fn get_events_variant(
+    tree: &Tree,
+    pallet_id: u8,
+    variant_id: u8,
+    query: &EventQuery,
+) -> (Vec<Event>, Option<Event>) {
+    get_events_page(
+        tree,
+        |block_number, event_index| {
+            VariantKey {
+                pallet_index: pallet_id,
+                variant_index: variant_id,
+                block_number: block_number.into(),
+                event_index: event_index.into(),
+            }
+            .as_bytes()
+            .to_vec()
+        },
+        |key| {
+            let key = VariantKey::read_from(key).unwrap();
+            Event {
+                block_number: key.block_number.into(),
+                event_index: key.event_index.into(),
+            }
+        },
+        query,
+    )
+}
 
-        events.push(Event {
-            block_number: key.block_number.into(),
-            event_index: key.event_index.into(),
-        });
+pub fn get_events_bytes32(
+    tree: &Tree,
+    key: &Bytes32,
+    query: &EventQuery,
+) -> (Vec<Event>, Option<Event>) {
+    get_events_page(
+        tree,
+        |block_number, event_index| {
+            Bytes32Key {
+                key: key.0,
+                block_number: block_number.into(),
+                event_index: event_index.into(),
+            }
+            .as_bytes()
+            .to_vec()
+        },
+        |key| {
+            let key = Bytes32Key::read_from(key).unwrap();
+            Event {
+                block_number: key.block_number.into(),
+                event_index: key.event_index.into(),
+            }
+        },
+        query,
+    )
+}
 
-        if events.len() == 100 {
-            break;
-        }
-    }
-    events
+pub fn get_events_u32(tree: &Tree, key: u32, query: &EventQuery) -> (Vec<Event>, Option<Event>) {
+    get_events_page(
+        tree,
+        |block_number, event_index| {
+            U32Key {
+                key: key.into(),
+                block_number: block_number.into(),
+                event_index: event_index.into(),
+            }
+            .as_bytes()
+            .to_vec()
+        },
+        |key| {
+            let key = U32Key::read_from(key).unwrap();
+            Event {
+                block_number: key.block_number.into(),
+                event_index: key.event_index.into(),
+            }
+        },
+        query,
+    )
 }
 
 pub fn process_msg_get_events_substrate<R: RuntimeIndexer>(
     trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
     key: &SubstrateKey,
-) -> Vec<Event> {
+    query: &EventQuery,
+) -> (Vec<Event>, Option<Event>) {
     match key {
         SubstrateKey::AccountId(account_id) => {
-            get_events_bytes32(&trees.substrate.account_id, account_id)
+            get_events_bytes32(&trees.substrate.account_id, account_id, query)
         }
         SubstrateKey::AccountIndex(account_index) => {
-            get_events_u32(&trees.substrate.account_index, *account_index)
+            get_events_u32(&trees.substrate.account_index, *account_index, query)
         }
         SubstrateKey::BountyIndex(bounty_index) => {
-            get_events_u32(&trees.substrate.bounty_index, *bounty_index)
+            get_events_u32(&trees.substrate.bounty_index, *bounty_index, query)
+        }
+        SubstrateKey::EraIndex(era_index) => {
+            get_events_u32(&trees.substrate.era_index, *era_index, query)
         }
-        SubstrateKey::EraIndex(era_index) => get_events_u32(&trees.substrate.era_index, *era_index),
         SubstrateKey::MessageId(message_id) => {
-            get_events_bytes32(&trees.substrate.message_id, message_id)
+            get_events_bytes32(&trees.substrate.message_id, message_id, query)
         }
-        SubstrateKey::PoolId(pool_id) => get_events_u32(&trees.substrate.pool_id, *pool_id),
+        SubstrateKey::PoolId(pool_id) => get_events_u32(&trees.substrate.pool_id, *pool_id, query),
         SubstrateKey::PreimageHash(preimage_hash) => {
-            get_events_bytes32(&trees.substrate.preimage_hash, preimage_hash)
+            get_events_bytes32(&trees.substrate.preimage_hash, preimage_hash, query)
         }
         SubstrateKey::ProposalHash(proposal_hash) => {
-            get_events_bytes32(&trees.substrate.proposal_hash, proposal_hash)
+            get_events_bytes32(&trees.substrate.proposal_hash, proposal_hash, query)
         }
         SubstrateKey::ProposalIndex(proposal_index) => {
-            get_events_u32(&trees.substrate.proposal_index, *proposal_index)
+            get_events_u32(&trees.substrate.proposal_index, *proposal_index, query)
+        }
+        SubstrateKey::RefIndex(ref_index) => {
+            get_events_u32(&trees.substrate.ref_index, *ref_index, query)
         }
-        SubstrateKey::RefIndex(ref_index) => get_events_u32(&trees.substrate.ref_index, *ref_index),
         SubstrateKey::RegistrarIndex(registrar_index) => {
-            get_events_u32(&trees.substrate.registrar_index, *registrar_index)
+            get_events_u32(&trees.substrate.registrar_index, *registrar_index, query)
         }
         SubstrateKey::SessionIndex(session_index) => {
-            get_events_u32(&trees.substrate.session_index, *session_index)
+            get_events_u32(&trees.substrate.session_index, *session_index, query)
+        }
+        SubstrateKey::TipHash(tip_hash) => {
+            get_events_bytes32(&trees.substrate.tip_hash, tip_hash, query)
         }
-        SubstrateKey::TipHash(tip_hash) => get_events_bytes32(&trees.substrate.tip_hash, tip_hash),
     }
 }
 
-pub fn process_msg_get_events<R: RuntimeIndexer>(
+pub fn get_key_events<R: RuntimeIndexer>(
     trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
-    key: Key<R::ChainKey>,
-) -> ResponseMessage<R::ChainKey> {
-    let events = match key {
+    key: &Key<R::ChainKey>,
+    query: &EventQuery,
+) -> (Vec<Event>, Option<Event>) {
+    match key {
         Key::Variant(pallet_id, variant_id) => {
-            get_events_variant(&trees.variant, pallet_id, variant_id)
+            get_events_variant(&trees.variant, *pallet_id, *variant_id, query)
         }
-        Key::Substrate(ref key) => process_msg_get_events_substrate::<R>(trees, key),
-        Key::Chain(ref key) => key.get_key_events(&trees.chain),
+        Key::Substrate(key) => process_msg_get_events_substrate::<R>(trees, key, query),
+        Key::Chain(key) => key.get_key_events(&trees.chain, query),
+    }
+}
+
+/// Gets a page of events for a key in a request once its variant name has been resolved.
+pub fn get_resolved_key_events<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    resolved: &KeyExpression<R::ChainKey>,
+    query: &EventQuery,
+) -> (Vec<Event>, Option<Event>) {
+    match resolved {
+        KeyExpression::Key(key) => get_key_events::<R>(trees, key, query),
+        // The variants a name has had in any runtime version
+        resolved => get_expression_events::<R>(trees, resolved, query),
+    }
+}
+
+pub fn process_msg_get_events<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    key: RequestKey<R::ChainKey>,
+    versions: &[Vec<PalletMeta>],
+    query: &EventQuery,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    let resolved = resolve_expression(&key.clone().into(), versions)?;
+    let (events, next_cursor) = get_resolved_key_events::<R>(trees, &resolved, query);
+    Ok(ResponseMessage::Events {
+        key,
+        events,
+        next_cursor,
+    })
+}
+
+/// Gets a page of events for each key in a batch, or a single page of the merged events if `merge` is set.
+pub fn process_msg_get_events_batch<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    keys: Vec<Key<R::ChainKey>>,
+    merge: bool,
+    query: &EventQuery,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    if keys.len() > MAX_BATCH_KEYS {
+        return Err(IndexError::LimitExceeded {
+            limit: keys.len(),
+            max: MAX_BATCH_KEYS,
+        });
+    }
+    if merge {
+        let expression = KeyExpression::Or(keys.into_iter().map(KeyExpression::Key).collect());
+        return process_msg_get_expression_events::<R>(trees, expression, &[], query);
+    }
+    let batch = keys
+        .into_iter()
+        .map(|key| {
+            let (events, next_cursor) = get_key_events::<R>(trees, &key, query);
+            KeyEvents {
+                key,
+                events,
+                next_cursor,
+            }
+        })
+        .collect();
+    Ok(ResponseMessage::EventsBatch(batch))
+}
+
+/// Counts the events for a key within an optional block range.
+pub fn count_key_events<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    key: &Key<R::ChainKey>,
+    from_block: Option<u32>,
+    to_block: Option<u32>,
+) -> u64 {
+    let query = EventQuery {
+        from_block,
+        to_block,
+        ..Default::default()
+    };
+    key_events_iter::<R>(trees, key, &query)
+        .count()
+        .try_into()
+        .unwrap()
+}
+
+pub fn process_msg_count_events<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    key: RequestKey<R::ChainKey>,
+    versions: &[Vec<PalletMeta>],
+    from_block: Option<u32>,
+    to_block: Option<u32>,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    let resolved = resolve_expression(&key.clone().into(), versions)?;
+    let query = EventQuery {
+        from_block,
+        to_block,
+        ..Default::default()
+    };
+    let count = expression_events_iter::<R>(trees, &resolved, &query)
+        .count()
+        .try_into()
+        .unwrap();
+    Ok(ResponseMessage::EventCount { key, count })
+}
+
+type EventIter<'a> = Box<dyn Iterator<Item = Event> + 'a>;
+
+/// Iterates all the events for a key that match a query, fetching them from the key tree a page at a time.
+fn key_events_iter<'a, R: RuntimeIndexer>(
+    trees: &'a Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    key: &'a Key<R::ChainKey>,
+    query: &EventQuery,
+) -> EventIter<'a> {
+    let mut query = EventQuery {
+        limit: Some(MAX_EVENTS_LIMIT),
+        ..query.clone()
     };
-    ResponseMessage::Events { key, events }
+    let mut page = Vec::new().into_iter();
+    let mut is_last_page = false;
+
+    Box::new(iter::from_fn(move || loop {
+        if let Some(event) = page.next() {
+            return Some(event);
+        }
+        if is_last_page {
+            return None;
+        }
+        let (events, next_cursor) = get_key_events::<R>(trees, key, &query);
+        is_last_page = next_cursor.is_none();
+        query.cursor = next_cursor;
+        page = events.into_iter();
+    }))
+}
+
+/// Compares two events by which comes first in the order.
+fn cmp_events(order: Order, a: &Event, b: &Event) -> Ordering {
+    match order {
+        Order::Descending => b.cmp(a),
+        Order::Ascending => a.cmp(b),
+    }
+}
+
+/// Merges two ordered event streams, keeping events that are in both.
+fn intersect_events<'a>(a: EventIter<'a>, b: EventIter<'a>, order: Order) -> EventIter<'a> {
+    let mut a = a.peekable();
+    let mut b = b.peekable();
+
+    Box::new(iter::from_fn(move || loop {
+        match (a.peek(), b.peek()) {
+            (Some(event_a), Some(event_b)) => match cmp_events(order, event_a, event_b) {
+                Ordering::Equal => {
+                    b.next();
+                    return a.next();
+                }
+                Ordering::Less => {
+                    a.next();
+                }
+                Ordering::Greater => {
+                    b.next();
+                }
+            },
+            _ => return None,
+        }
+    }))
+}
+
+/// Merges two ordered event streams, keeping events that are in either without duplicates.
+fn union_events<'a>(a: EventIter<'a>, b: EventIter<'a>, order: Order) -> EventIter<'a> {
+    let mut a = a.peekable();
+    let mut b = b.peekable();
+
+    Box::new(iter::from_fn(move || match (a.peek(), b.peek()) {
+        (Some(event_a), Some(event_b)) => match cmp_events(order, event_a, event_b) {
+            Ordering::Equal => {
+                b.next();
+                a.next()
+            }
+            Ordering::Less => a.next(),
+            Ordering::Greater => b.next(),
+        },
+        (Some(_), None) => a.next(),
+        (None, _) => b.next(),
+    }))
+}
+
+fn expression_events_iter<'a, R: RuntimeIndexer>(
+    trees: &'a Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    expression: &'a KeyExpression<R::ChainKey>,
+    query: &EventQuery,
+) -> EventIter<'a> {
+    let (operands, merge): (_, fn(EventIter<'a>, EventIter<'a>, Order) -> EventIter<'a>) =
+        match expression {
+            KeyExpression::Key(key) => return key_events_iter::<R>(trees, key, query),
+            // Variant names must be resolved first.
+            KeyExpression::VariantName { .. } => return Box::new(iter::empty()),
+            KeyExpression::And(operands) => (operands, intersect_events),
+            KeyExpression::Or(operands) => (operands, union_events),
+        };
+    operands
+        .iter()
+        .map(|operand| expression_events_iter::<R>(trees, operand, query))
+        .reduce(|a, b| merge(a, b, query.order))
+        .unwrap_or_else(|| Box::new(iter::empty()))
+}
+
+/// Evaluates a boolean expression over keys, returning a page of matching events and the cursor for the next page.
+pub fn get_expression_events<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    expression: &KeyExpression<R::ChainKey>,
+    query: &EventQuery,
+) -> (Vec<Event>, Option<Event>) {
+    let iter = expression_events_iter::<R>(trees, expression, query);
+    take_page(iter, query_limit(query))
+}
+
+/// Replaces variant names in an expression with the variant indexes they have had in any of the versions of pallet metadata.
+pub fn resolve_expression<CK: IndexKey + Clone>(
+    expression: &KeyExpression<CK>,
+    versions: &[Vec<PalletMeta>],
+) -> Result<KeyExpression<CK>, IndexError> {
+    Ok(match expression {
+        KeyExpression::Key(key) => KeyExpression::Key(key.clone()),
+        KeyExpression::VariantName { pallet, event } => {
+            let mut variants = BTreeSet::new();
+            for pallets in versions {
+                for pallet_meta in pallets.iter().filter(|meta| meta.name == *pallet) {
+                    for event_meta in pallet_meta.events.iter().filter(|meta| meta.name == *event) {
+                        variants.insert((pallet_meta.index, event_meta.index));
+                    }
+                }
+            }
+            if variants.is_empty() {
+                return Err(IndexError::VariantNotFound {
+                    pallet: pallet.clone(),
+                    event: event.clone(),
+                });
+            }
+            KeyExpression::Or(
+                variants
+                    .into_iter()
+                    .map(|(pallet_index, variant_index)| {
+                        KeyExpression::Key(Key::Variant(pallet_index, variant_index))
+                    })
+                    .collect(),
+            )
+        }
+        KeyExpression::And(operands) => KeyExpression::And(
+            operands
+                .iter()
+                .map(|operand| resolve_expression(operand, versions))
+                .collect::<Result<_, _>>()?,
+        ),
+        KeyExpression::Or(operands) => KeyExpression::Or(
+            operands
+                .iter()
+                .map(|operand| resolve_expression(operand, versions))
+                .collect::<Result<_, _>>()?,
+        ),
+    })
+}
+
+pub fn process_msg_get_expression_events<R: RuntimeIndexer>(
+    trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    expression: KeyExpression<R::ChainKey>,
+    versions: &[Vec<PalletMeta>],
+    query: &EventQuery,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    let resolved = resolve_expression(&expression, versions)?;
+    let (events, next_cursor) = get_expression_events::<R>(trees, &resolved, query);
+    Ok(ResponseMessage::ExpressionEvents {
+        expression,
+        events,
+        next_cursor,
+    })
 }
 
 pub fn process_msg_subscribe_events<R: RuntimeIndexer>(
-    key: Key<R::ChainKey>,
+    key: RequestKey<R::ChainKey>,
+    versions: &[Vec<PalletMeta>],
+    since: Option<Event>,
+    id: Option<u32>,
     sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
-    sub_response_tx: &UnboundedSender<ResponseMessage<R::ChainKey>>,
-) -> ResponseMessage<R::ChainKey> {
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
+) -> Result<(), IndexError> {
+    let resolved = resolve_expression(&key.clone().into(), versions)?;
     let msg = SubscriptionMessage::SubscribeEvents {
         key,
+        resolved,
+        since,
+        id,
         sub_response_tx: sub_response_tx.clone(),
     };
     sub_tx.send(msg).unwrap();
-    ResponseMessage::Subscribed
+    Ok(())
 }
 
 pub fn process_msg_unsubscribe_events<R: RuntimeIndexer>(
-    key: Key<R::ChainKey>,
+    key: RequestKey<R::ChainKey>,
+    versions: &[Vec<PalletMeta>],
     sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
-    sub_response_tx: &UnboundedSender<ResponseMessage<R::ChainKey>>,
-) -> ResponseMessage<R::ChainKey> {
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    let resolved = resolve_expression(&key.into(), versions)?;
     let msg = SubscriptionMessage::UnsubscribeEvents {
-        key,
+        resolved,
         sub_response_tx: sub_response_tx.clone(),
     };
     sub_tx.send(msg).unwrap();
-    ResponseMessage::Unsubscribed
+    Ok(ResponseMessage::Unsubscribed)
+}
+
+/// Checks the page size of an event query.
+pub fn check_query(query: &EventQuery) -> Result<(), IndexError> {
+    match query.limit {
+        Some(limit) if limit > MAX_EVENTS_LIMIT => Err(IndexError::LimitExceeded {
+            limit: limit.into(),
+            max: MAX_EVENTS_LIMIT.into(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Parses a WebSocket message into a request, also returning the request id if there is one.
+pub fn parse_msg<R: RuntimeIndexer>(
+    msg: &tungstenite::Message,
+) -> (Option<u32>, Result<RequestMessage<R::ChainKey>, IndexError>) {
+    let value: serde_json::Value = match msg.to_text() {
+        Ok(text) => match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(error) => return (None, Err(error.into())),
+        },
+        Err(_) => return (None, Err(IndexError::ParseError)),
+    };
+    match serde_json::from_value::<Request<R::ChainKey>>(value.clone()) {
+        Ok(request) => (request.id, Ok(request.msg)),
+        Err(error) => {
+            // Echo the id even if the rest of the request is invalid.
+            let id = value
+                .get("id")
+                .and_then(serde_json::Value::as_u64)
+                .and_then(|id| id.try_into().ok());
+            // Report a more specific error if the key could not be parsed.
+            if let Some(Err(error)) = value
+                .get("key")
+                .map(|key| serde_json::from_value::<RequestKey<R::ChainKey>>(key.clone()))
+            {
+                return (id, Err(IndexError::UnknownKeyType(error)));
+            }
+            (id, Err(error.into()))
+        }
+    }
+}
+
+/// Converts an error into a JSON error response.
+pub fn process_error<R: RuntimeIndexer>(error: IndexError) -> ResponseMessage<R::ChainKey> {
+    let (code, message) = match &error {
+        IndexError::ParseError | IndexError::Hex(_) => (ErrorCode::ParseError, error.to_string()),
+        IndexError::Json(json_error) => {
+            (ErrorCode::ParseError, format!("{}: {}", error, json_error))
+        }
+        IndexError::VariantNotFound { pallet, event } => (
+            ErrorCode::UnknownKeyType,
+            format!("{}: {}.{}", error, pallet, event),
+        ),
+        IndexError::UnknownKeyType(json_error) => (
+            ErrorCode::UnknownKeyType,
+            format!("{}: {}", error, json_error),
+        ),
+        IndexError::LimitExceeded { limit, max } => (
+            ErrorCode::LimitExceeded,
+            format!("{}: {} is more than {}", error, limit, max),
+        ),
+        IndexError::Storage(_)
+        | IndexError::SchemaTooNew { .. }
+        | IndexError::MigrationNotFound { .. }
+        | IndexError::InvalidSnapshot(_)
+        | IndexError::MergeConflict(_)
+        | IndexError::Io(_)
+        | IndexError::Subxt(_)
+        | IndexError::Tungstenite(_)
+        | IndexError::BlockNotFound(_)
+        | IndexError::SubscriptionClosed
+        | IndexError::WrongGenesisHash(_) => (ErrorCode::BackendUnavailable, error.to_string()),
+        IndexError::InvalidBlockRange => (ErrorCode::ParseError, error.to_string()),
+        IndexError::AdminDisabled => (ErrorCode::Forbidden, error.to_string()),
+    };
+    ResponseMessage::Error { code, message }
+}
+
+/// Asks the indexer thread to re-index a range of blocks.
+pub fn process_msg_reindex<R: RuntimeIndexer>(
+    from_block: u32,
+    to_block: u32,
+    admin_tx: Option<&UnboundedSender<AdminMessage>>,
+) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
+    let admin_tx = admin_tx.ok_or(IndexError::AdminDisabled)?;
+    if from_block > to_block {
+        return Err(IndexError::InvalidBlockRange);
+    }
+    let span = Span {
+        start: from_block,
+        end: to_block,
+    };
+    admin_tx
+        .send(AdminMessage::Reindex { span: span.clone() })
+        .unwrap();
+    Ok(ResponseMessage::Reindexing(span))
+}
+
+#[allow(clippy::too_many_arguments)]
+/// Returns the pallet metadata of each spec version, for resolving variant names.
+async fn get_versions(metadata_map_lock: &RwLock<MetadataMap>) -> Vec<Vec<PalletMeta>> {
+    metadata_map_lock
+        .read()
+        .await
+        .values()
+        .map(get_pallet_metas)
+        .collect()
+}
+
+/// Returns the pallet metadata of each spec version if the key is a variant name.
+async fn get_key_versions<CK: IndexKey>(
+    key: &RequestKey<CK>,
+    metadata_map_lock: &RwLock<MetadataMap>,
+) -> Vec<Vec<PalletMeta>> {
+    match key {
+        RequestKey::VariantName { .. } => get_versions(metadata_map_lock).await,
+        _ => Vec::new(),
+    }
 }
 
 pub async fn process_msg<R: RuntimeIndexer>(
     rpc: &LegacyRpcMethods<R::RuntimeConfig>,
     trees: &Trees<<R::ChainKey as IndexKey>::ChainTrees>,
+    metadata_map_lock: &RwLock<MetadataMap>,
     msg: RequestMessage<R::ChainKey>,
+    id: Option<u32>,
     sub_tx: &UnboundedSender<SubscriptionMessage<R::ChainKey>>,
-    sub_response_tx: &UnboundedSender<ResponseMessage<R::ChainKey>>,
-) -> Result<ResponseMessage<R::ChainKey>, IndexError> {
-    Ok(match msg {
-        RequestMessage::Status => process_msg_status::<R>(&trees.span),
+    sub_response_tx: &UnboundedSender<Response<R::ChainKey>>,
+    admin_tx: Option<&UnboundedSender<AdminMessage>>,
+) -> Result<Option<ResponseMessage<R::ChainKey>>, IndexError> {
+    Ok(Some(match msg {
+        RequestMessage::Status => process_msg_status::<R>(trees),
         RequestMessage::SubscribeStatus => {
-            process_msg_subscribe_status::<R>(sub_tx, sub_response_tx)
+            process_msg_subscribe_status::<R>(id, sub_tx, sub_response_tx)
         }
         RequestMessage::UnsubscribeStatus => {
             process_msg_unsubscribe_status::<R>(sub_tx, sub_response_tx)
         }
         RequestMessage::Variants => process_msg_variants::<R>(rpc).await?,
-        RequestMessage::GetEvents { key } => process_msg_get_events::<R>(trees, key),
-        RequestMessage::SubscribeEvents { key } => {
-            process_msg_subscribe_events::<R>(key, sub_tx, sub_response_tx)
+        RequestMessage::GetEvents { key, query } => {
+            check_query(&query)?;
+            let versions = get_key_versions(&key, metadata_map_lock).await;
+            process_msg_get_events::<R>(trees, key, &versions, &query)?
+        }
+        RequestMessage::GetExpressionEvents { expression, query } => {
+            check_query(&query)?;
+            let versions = get_versions(metadata_map_lock).await;
+            process_msg_get_expression_events::<R>(trees, expression, &versions, &query)?
+        }
+        RequestMessage::GetEventsBatch { keys, merge, query } => {
+            check_query(&query)?;
+            process_msg_get_events_batch::<R>(trees, keys, merge, &query)?
+        }
+        RequestMessage::CountEvents {
+            key,
+            from_block,
+            to_block,
+        } => {
+            let versions = get_key_versions(&key, metadata_map_lock).await;
+            process_msg_count_events::<R>(trees, key, &versions, from_block, to_block)?
+        }
+        RequestMessage::SubscribeEvents { key, since } => {
+            let versions = get_key_versions(&key, metadata_map_lock).await;
+            // The indexer thread answers once it has checked the events to replay.
+            process_msg_subscribe_events::<R>(key, &versions, since, id, sub_tx, sub_response_tx)?;
+            return Ok(None);
         }
         RequestMessage::UnsubscribeEvents { key } => {
-            process_msg_unsubscribe_events::<R>(key, sub_tx, sub_response_tx)
+            let versions = get_key_versions(&key, metadata_map_lock).await;
+            process_msg_unsubscribe_events::<R>(key, &versions, sub_tx, sub_response_tx)?
         }
         RequestMessage::SizeOnDisk => ResponseMessage::SizeOnDisk(trees.root.size_on_disk()?),
-    })
+        RequestMessage::Reindex {
+            from_block,
+            to_block,
+        } => process_msg_reindex::<R>(from_block, to_block, admin_tx)?,
+    }))
 }
 
 async fn handle_connection<R: RuntimeIndexer>(
-    rpc: LegacyRpcMethods<R::RuntimeConfig>,
+    rpc_rx: Receiver<LegacyRpcMethods<R::RuntimeConfig>>,
+    metadata_map_lock: Arc<RwLock<MetadataMap>>,
     raw_stream: TcpStream,
     addr: SocketAddr,
     trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
     sub_tx: UnboundedSender<SubscriptionMessage<R::ChainKey>>,
+    admin_tx: Option<UnboundedSender<AdminMessage>>,
 ) -> Result<(), IndexError> {
     info!("Incoming TCP connection from: {}", addr);
     let ws_stream = tokio_tungstenite::accept_async(raw_stream).await?;
@@ -254,14 +799,25 @@ async fn handle_connection<R: RuntimeIndexer>(
         tokio::select! {
             Some(Ok(msg)) = ws_receiver.next() => {
                 if msg.is_text() || msg.is_binary() {
-                    match serde_json::from_str(msg.to_text()?) {
-                        Ok(request_json) => {
-                            let response_msg = process_msg::<R>(&rpc, &trees, request_json, &sub_tx, &sub_events_tx).await?;
-                            let response_json = serde_json::to_string(&response_msg).unwrap();
-                            ws_sender.send(tungstenite::Message::Text(response_json)).await?;
-                        },
-                        Err(error) => error!("{}", error),
-                    }
+                    let (id, request_msg) = parse_msg::<R>(&msg);
+                    // The indexer thread replaces the RPC client when it reconnects to the node.
+                    let rpc = rpc_rx.borrow().clone();
+                    let response_msg = match request_msg {
+                        Ok(request_msg) => process_msg::<R>(&rpc, &trees, &metadata_map_lock, request_msg, id, &sub_tx, &sub_events_tx, admin_tx.as_ref()).await,
+                        Err(error) => Err(error),
+                    };
+                    let response_msg = match response_msg {
+                        Ok(Some(response_msg)) => response_msg,
+                        // The response is sent by the indexer thread.
+                        Ok(None) => continue,
+                        Err(error) => {
+                            error!("{}: {:?}", addr, error);
+                            process_error::<R>(error)
+                        }
+                    };
+                    let response = Response { id, msg: response_msg };
+                    let response_json = serde_json::to_string(&response).unwrap();
+                    ws_sender.send(tungstenite::Message::Text(response_json)).await?;
                 }
             },
             Some(msg) = sub_events_rx.recv() => {
@@ -274,10 +830,12 @@ async fn handle_connection<R: RuntimeIndexer>(
 
 pub async fn websockets_listen<R: RuntimeIndexer + 'static>(
     trees: Trees<<R::ChainKey as IndexKey>::ChainTrees>,
-    rpc: LegacyRpcMethods<R::RuntimeConfig>,
+    rpc_rx: Receiver<LegacyRpcMethods<R::RuntimeConfig>>,
+    metadata_map_lock: Arc<RwLock<MetadataMap>>,
     port: u16,
     mut exit_rx: Receiver<bool>,
     sub_tx: UnboundedSender<SubscriptionMessage<R::ChainKey>>,
+    admin: Option<(u16, UnboundedSender<AdminMessage>)>,
 ) {
     let mut addr = "0.0.0.0:".to_string();
     addr.push_str(&port.to_string());
@@ -287,6 +845,19 @@ pub async fn websockets_listen<R: RuntimeIndexer + 'static>(
     let listener = try_socket.expect("Failed to bind");
     info!("Listening on: {}", addr);
 
+    // Admin requests are only served on a separate listener that only accepts local connections.
+    let (admin_listener, admin_tx) = match admin {
+        Some((admin_port, admin_tx)) => {
+            let admin_addr = format!("127.0.0.1:{}", admin_port);
+            let admin_listener = TcpListener::bind(&admin_addr)
+                .await
+                .expect("Failed to bind admin");
+            info!("Listening for admin requests on: {}", admin_addr);
+            (Some(admin_listener), Some(admin_tx))
+        }
+        None => (None, None),
+    };
+
     // Let's spawn the handling of each connection in a separate task.
     loop {
         tokio::select! {
@@ -297,11 +868,24 @@ pub async fn websockets_listen<R: RuntimeIndexer + 'static>(
             }
             Ok((stream, addr)) = listener.accept() => {
                 tokio::spawn(handle_connection::<R>(
-                    rpc.clone(),
+                    rpc_rx.clone(),
+                    metadata_map_lock.clone(),
+                    stream,
+                    addr,
+                    trees.clone(),
+                    sub_tx.clone(),
+                    None,
+                ));
+            }
+            Ok((stream, addr)) = async { admin_listener.as_ref().unwrap().accept().await }, if admin_listener.is_some() => {
+                tokio::spawn(handle_connection::<R>(
+                    rpc_rx.clone(),
+                    metadata_map_lock.clone(),
                     stream,
                     addr,
                     trees.clone(),
                     sub_tx.clone(),
+                    admin_tx.clone(),
                 ));
             }
         }